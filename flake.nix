@@ -25,17 +25,6 @@
         pkgs = import nixpkgs {
           inherit system;
         };
-
-        sqlx-db = pkgs.runCommand "sqlx-db-prepare"
-          {
-            nativeBuildInputs = with pkgs; [ sqlx-cli ];
-          } '' 
-          mkdir $out
-          export DATABASE_URL=sqlite:$out/bridge.sqlite3
-          sqlx database create
-          echo hi there
-          sqlx migrate run --source ${./migrations}
-        '';
       in
       {
 
@@ -43,9 +32,6 @@
           src = ./.;
 
 
-          DATABASE_URL = "sqlite://${sqlx-db}/bridge.sqlite3?immutable=true";
-          BRIDGE_SQLITE_PATH = "sqlite://${sqlx-db}/bridge.sqlite3?immutable=true";
-
           nativeBuildInputs = with pkgs; [
             rust-analyzer
             pkg-config
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE users;
+DROP FUNCTION users_set_updated_at;
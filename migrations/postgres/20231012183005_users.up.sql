@@ -0,0 +1,29 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS users
+(
+    -- The nick folded with the irc casemapping by the bridge, so nicks that irc treats as the
+    -- same are one user
+    ircnick_key TEXT PRIMARY KEY NOT NULL,
+    ircnick TEXT NOT NULL,
+    discordid BIGINT,
+    discordnick TEXT,
+    discordname TEXT,
+    verified BOOLEAN NOT NULL DEFAULT false,
+    avatar TEXT,
+    -- Folded like the nick
+    ircaccount TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX users_discordid ON users (discordid);
+
+CREATE FUNCTION users_set_updated_at() RETURNS trigger AS $$
+BEGIN
+    NEW.updated_at = now();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_updated_at BEFORE UPDATE ON users
+FOR EACH ROW EXECUTE FUNCTION users_set_updated_at();
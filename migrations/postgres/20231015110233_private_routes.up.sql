@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE private_routes
+(
+    ircnick TEXT PRIMARY KEY NOT NULL,
+    discordid BIGINT NOT NULL,
+    last_active BIGINT NOT NULL
+);
+
+CREATE INDEX private_routes_discordid ON private_routes (discordid, last_active);
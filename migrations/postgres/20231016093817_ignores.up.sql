@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE ignores
+(
+    kind TEXT NOT NULL,
+    value TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (kind, value)
+);
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE audit_log
+(
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    side TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    allowed BOOLEAN NOT NULL
+);
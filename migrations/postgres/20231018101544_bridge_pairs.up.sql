@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE bridge_pairs
+(
+    ircchannel TEXT NOT NULL,
+    discordchannel BIGINT NOT NULL,
+    direction TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (ircchannel, discordchannel)
+);
@@ -1,2 +1,2 @@
 -- Add down migration script here
-DELETE TABLE users
+DROP TABLE users;
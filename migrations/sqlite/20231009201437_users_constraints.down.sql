@@ -0,0 +1,20 @@
+-- Add down migration script here
+DROP TRIGGER users_updated_at;
+DROP INDEX users_discordid;
+
+CREATE TABLE users_old
+(
+    ircnick TEXT PRIMARY KEY NOT NULL,
+    discordid INTEGER,
+    discordnick TEXT,
+    discordname TEXT,
+    verified BOOLEAN,
+    avatar TEXT,
+    ircaccount TEXT
+);
+
+INSERT INTO users_old (ircnick, discordid, discordnick, discordname, verified, avatar, ircaccount)
+SELECT ircnick, discordid, discordnick, discordname, verified, avatar, ircaccount FROM users;
+
+DROP TABLE users;
+ALTER TABLE users_old RENAME TO users;
@@ -0,0 +1,51 @@
+-- Add up migration script here
+-- SQLite cannot add constraints to an existing table, so it is rebuilt
+CREATE TABLE users_new
+(
+    ircnick TEXT PRIMARY KEY NOT NULL,
+    discordid INTEGER,
+    discordnick TEXT,
+    discordname TEXT,
+    verified BOOLEAN NOT NULL DEFAULT false,
+    avatar TEXT,
+    ircaccount TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- /connect_user used to store the discord name in discordid and the id in discordnick
+INSERT INTO users_new (ircnick, discordid, discordnick, discordname, verified, avatar, ircaccount)
+SELECT
+    ircnick,
+    CASE WHEN typeof(discordid) = 'text' AND discordnick GLOB '[0-9]*'
+        THEN CAST(discordnick AS INTEGER) ELSE discordid END,
+    CASE WHEN typeof(discordid) = 'text' AND discordnick GLOB '[0-9]*'
+        THEN discordid ELSE discordnick END,
+    discordname,
+    COALESCE(verified, false),
+    avatar,
+    ircaccount
+FROM users;
+
+-- A discord user can only be linked once, keep their verified link or else the oldest one
+UPDATE users_new
+SET discordid = NULL, discordnick = NULL, discordname = NULL, verified = false
+WHERE discordid IS NOT NULL AND rowid NOT IN (
+    SELECT (
+        SELECT best.rowid FROM users_new AS best
+        WHERE best.discordid = linked.discordid
+        ORDER BY best.verified DESC, best.rowid
+        LIMIT 1
+    )
+    FROM (SELECT DISTINCT discordid FROM users_new WHERE discordid IS NOT NULL) AS linked
+);
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE UNIQUE INDEX users_discordid ON users (discordid);
+
+CREATE TRIGGER users_updated_at AFTER UPDATE ON users FOR EACH ROW
+BEGIN
+    UPDATE users SET updated_at = CURRENT_TIMESTAMP WHERE ircnick = NEW.ircnick;
+END;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE ignores
+(
+    kind TEXT NOT NULL,
+    value TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (kind, value)
+);
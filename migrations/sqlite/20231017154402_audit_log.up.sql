@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE audit_log
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    side TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    allowed BOOLEAN NOT NULL
+);
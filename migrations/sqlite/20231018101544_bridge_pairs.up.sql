@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE bridge_pairs
+(
+    ircchannel TEXT NOT NULL,
+    discordchannel INTEGER NOT NULL,
+    direction TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (ircchannel, discordchannel)
+);
@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE optouts
+(
+    kind TEXT NOT NULL,
+    value TEXT NOT NULL,
+    notified BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (kind, value)
+);
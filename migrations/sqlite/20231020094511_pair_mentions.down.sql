@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE bridge_pairs DROP COLUMN allow_mentions;
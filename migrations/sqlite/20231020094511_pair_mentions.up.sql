@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE bridge_pairs ADD COLUMN allow_mentions BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,30 @@
+-- Add down migration script here
+CREATE TABLE users_old
+(
+    ircnick TEXT PRIMARY KEY NOT NULL,
+    discordid INTEGER,
+    discordnick TEXT,
+    discordname TEXT,
+    verified BOOLEAN NOT NULL DEFAULT false,
+    avatar TEXT,
+    ircaccount TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO users_old
+    (ircnick, discordid, discordnick, discordname, verified, avatar, ircaccount, created_at,
+     updated_at)
+SELECT ircnick, discordid, discordnick, discordname, verified, avatar, ircaccount, created_at,
+    updated_at
+FROM users;
+
+DROP TABLE users;
+ALTER TABLE users_old RENAME TO users;
+
+CREATE UNIQUE INDEX users_discordid ON users (discordid);
+
+CREATE TRIGGER users_updated_at AFTER UPDATE ON users FOR EACH ROW
+BEGIN
+    UPDATE users SET updated_at = CURRENT_TIMESTAMP WHERE ircnick = NEW.ircnick;
+END;
@@ -0,0 +1,49 @@
+-- Add up migration script here
+-- irc nicks are case insensitive, so users are keyed by the nick folded with the rfc1459
+-- casemapping, which the bridge computes when writing. Accounts are stored folded the same way.
+CREATE TABLE users_new
+(
+    ircnick_key TEXT PRIMARY KEY NOT NULL,
+    ircnick TEXT NOT NULL,
+    discordid INTEGER,
+    discordnick TEXT,
+    discordname TEXT,
+    verified BOOLEAN NOT NULL DEFAULT false,
+    avatar TEXT,
+    ircaccount TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Nicks that fold to the same key were stored as separate users, keep the verified or linked one
+INSERT OR IGNORE INTO users_new
+    (ircnick_key, ircnick, discordid, discordnick, discordname, verified, avatar, ircaccount,
+     created_at, updated_at)
+SELECT
+    replace(replace(replace(replace(lower(ircnick), '[', '{'), ']', '}'), '\', '|'), '~', '^'),
+    ircnick, discordid, discordnick, discordname, verified, avatar,
+    replace(replace(replace(replace(lower(ircaccount), '[', '{'), ']', '}'), '\', '|'), '~', '^'),
+    created_at, updated_at
+FROM users
+ORDER BY verified DESC, discordid IS NULL, updated_at DESC;
+
+-- and the newest avatar set for any of them
+UPDATE users_new SET avatar = (
+    SELECT other.avatar FROM users AS other
+    WHERE replace(replace(replace(replace(lower(other.ircnick), '[', '{'), ']', '}'), '\', '|'),
+            '~', '^') = users_new.ircnick_key
+        AND other.avatar IS NOT NULL
+    ORDER BY other.updated_at DESC
+    LIMIT 1
+)
+WHERE avatar IS NULL;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE UNIQUE INDEX users_discordid ON users (discordid);
+
+CREATE TRIGGER users_updated_at AFTER UPDATE ON users FOR EACH ROW
+BEGIN
+    UPDATE users SET updated_at = CURRENT_TIMESTAMP WHERE ircnick_key = NEW.ircnick_key;
+END;
@@ -0,0 +1,107 @@
+//! Which way messages flow between the bridged irc and Discord channels. Admins can pause the
+//! bridge or make it one way at runtime, and the setting is kept in the database.
+
+use std::sync::{Arc, RwLock};
+
+use clap::ValueEnum;
+
+use crate::irc_nick::fold_nick;
+use crate::user_store::UserStore;
+use crate::{Config, Result};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Bridge messages both ways
+    #[default]
+    Both,
+    /// Only relay irc messages to Discord
+    IrcToDiscord,
+    /// Only relay Discord messages to irc
+    DiscordToIrc,
+    /// Relay nothing
+    Paused,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::Both,
+        Direction::IrcToDiscord,
+        Direction::DiscordToIrc,
+        Direction::Paused,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Both => "both",
+            Direction::IrcToDiscord => "irc-to-discord",
+            Direction::DiscordToIrc => "discord-to-irc",
+            Direction::Paused => "paused",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|direction| direction.name() == name)
+    }
+
+    pub fn to_discord(self) -> bool {
+        matches!(self, Direction::Both | Direction::IrcToDiscord)
+    }
+
+    pub fn to_irc(self) -> bool {
+        matches!(self, Direction::Both | Direction::DiscordToIrc)
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Direction::Both => "Bridging messages both ways",
+            Direction::IrcToDiscord => "Only bridging messages from irc to discord",
+            Direction::DiscordToIrc => "Only bridging messages from discord to irc",
+            Direction::Paused => "Bridging is paused",
+        }
+    }
+}
+
+/// The direction of the channel pair in the config
+#[derive(Clone)]
+pub struct PairDirection {
+    store: Arc<dyn UserStore>,
+    irc_channel: String,
+    discord_channel: u64,
+    direction: Arc<RwLock<Direction>>,
+}
+
+impl PairDirection {
+    pub async fn load(store: Arc<dyn UserStore>, config: &Config) -> Result<Self> {
+        // irc channel names are case insensitive, so store them folded
+        let irc_channel = fold_nick(&config.irc_channel);
+        let direction = store
+            .pair_direction(&irc_channel, config.discord_channel)
+            .await?
+            .unwrap_or_default();
+        if direction != Direction::Both {
+            println!("LOG: {}", direction.describe());
+        }
+
+        Ok(Self {
+            store,
+            irc_channel,
+            discord_channel: config.discord_channel,
+            direction: Arc::new(RwLock::new(direction)),
+        })
+    }
+
+    pub fn get(&self) -> Direction {
+        *self.direction.read().expect("direction lock poisoned")
+    }
+
+    pub async fn set(&self, direction: Direction) -> Result<()> {
+        self.store
+            .set_pair_direction(&self.irc_channel, self.discord_channel, direction)
+            .await?;
+        *self.direction.write().expect("direction lock poisoned") = direction;
+        println!("LOG: {}", direction.describe());
+        Ok(())
+    }
+}
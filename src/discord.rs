@@ -1,37 +1,69 @@
 use regex::{Captures, Regex};
 use serenity::async_trait;
+use serenity::builder::{CreateEmbed, EditInteractionResponse};
 use serenity::http::Http;
 use serenity::model::channel::Message;
-use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
-use serenity::model::prelude::interaction::Interaction;
+use serenity::model::prelude::component::ButtonStyle;
+use serenity::model::prelude::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::model::prelude::interaction::message_component::MessageComponentInteraction;
+use serenity::model::prelude::interaction::{Interaction, InteractionResponseType};
 use serenity::model::prelude::GuildId;
 use serenity::model::prelude::Member;
 use serenity::model::prelude::UserId;
 use serenity::model::prelude::WebhookId;
 use serenity::model::user::User;
 use serenity::prelude::*;
-use sqlx::SqlitePool;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 
 use crate::IrcRequest;
 use crate::Result;
 
-use crate::irc_side::IrcResponseCallback;
+use crate::direction::{Direction, PairDirection};
+use crate::echo::EchoFilter;
+use crate::filters::{self, Filtered, Filters};
+use crate::ignores::{IgnoreCommand, IgnoreKind, IgnoreList};
+use crate::irc_format;
+use crate::irc_nick::NickTracker;
+use crate::irc_sanitise;
+use crate::irc_whois::{self, Whois};
+use crate::linking::{self, PendingLinks, CODE_LIFETIME};
+use crate::mentions::{MentionPolicy, PairMentions};
+use crate::optout::{OptOutStatus, OptOuts};
+use crate::permissions;
+use crate::user_store::{User as StoredUser, UserStore};
 use crate::BridgeSenders;
 use crate::Config;
 
+/// How long to wait for the irc server to answer NAMES and WHOIS
+const IRC_ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Slash commands only bridge admins may use
+const ADMIN_COMMANDS: [&str; 2] = ["bridge", "links"];
+/// Nicks shown per page of /users, keeping the embed well under Discord's field limits
+const NAMES_PER_PAGE: usize = 30;
+
 pub struct Handler {
     pub config: crate::Config,
     pub irc_sender: irc::client::Sender,
     pub client_ref: Arc<Mutex<irc::client::Client>>,
-    pub ignored_users: Vec<UserId>,
+    pub ignores: IgnoreList,
+    pub direction: PairDirection,
+    pub opt_outs: OptOuts,
+    pub mentions: PairMentions,
+    pub echoes: EchoFilter,
+    pub filters: Filters,
     pub webhook_id: WebhookId,
-    pub database_pool: SqlitePool,
+    pub users: Arc<dyn UserStore>,
     pub senders: BridgeSenders,
+    pub pending_links: PendingLinks,
+    pub nick: NickTracker,
 }
 
 pub async fn discord_receiver(mut discord_client: Client) -> Result<()> {
@@ -40,68 +72,558 @@ pub async fn discord_receiver(mut discord_client: Client) -> Result<()> {
 }
 
 impl Handler {
+    /// Checks that the user may run an admin command, recording the attempt in the audit log and
+    /// answering them if they may not
+    async fn check_admin(&self, ctx: &Context, command: &ApplicationCommandInteraction) -> bool {
+        let allowed =
+            permissions::is_discord_admin(&self.config, command.user.id, command.member.as_ref());
+        let actor = format!("{} ({})", command.user.tag(), command.user.id);
+        permissions::audit(
+            self.users.as_ref(),
+            "discord",
+            &actor,
+            &command_line(command),
+            allowed,
+        )
+        .await;
+
+        if !allowed {
+            reply_ephemeral(ctx, command, "Only bridge admins can do that".into()).await;
+        }
+        allowed
+    }
+
     fn should_ignore_message(&self, ctx: &Context, message: &Message) -> bool {
         message.is_own(&ctx.cache)
-            || self.ignored_users.contains(&message.author.id)
+            || self.ignores.is_discord_ignored(message.author.id.0)
             || (message.webhook_id == Some(self.webhook_id))
+            || self.config.discord_relay_bots.contains(&message.author.id.0)
+            // Only bots echo, so people repeating what was just said are still bridged
+            || (message.author.bot && self.echoes.is_discord_echo(&message.content))
     }
 
-    async fn handle_names_command(&self, ctx: &Context, command: ApplicationCommandInteraction) {
+    /// Sends a request to the irc side and waits for its answer, giving up if the server is slow
+    async fn ask_irc<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<T>) -> IrcRequest,
+    ) -> std::result::Result<T, String> {
+        let (reply, answer) = oneshot::channel();
         self.senders
             .irc
-            .send(IrcRequest::Names {
-                interaction: command,
+            .send(request(reply))
+            .await
+            .map_err(|e| format!("Could not reach the irc side: {e}"))?;
+
+        match tokio::time::timeout(IRC_ANSWER_TIMEOUT, answer).await {
+            Ok(Ok(answer)) => Ok(answer),
+            _ => Err("The irc server did not answer in time".to_string()),
+        }
+    }
+
+    async fn handle_names_command(&self, ctx: &Context, command: ApplicationCommandInteraction) {
+        // The irc server can take a while to answer, so acknowledge the command right away
+        if let Err(e) = command
+            .create_interaction_response(&ctx.http, |w| {
+                w.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|w| w.ephemeral(true))
             })
             .await
-            .expect("Could not send message to irc handler");
+        {
+            println!("LOG: Could not respond to discord interaction: {e}");
+            return;
+        }
+
+        let names = self.ask_irc(|reply| IrcRequest::Names { reply }).await;
+        if let Err(e) = command
+            .edit_original_interaction_response(&ctx.http, |w| {
+                names_response(w, &self.config.irc_channel, names, 0)
+            })
+            .await
+        {
+            println!("LOG: Could not send names to discord: {e}");
+        }
     }
 
-    async fn handle_connect_user_command(
+    /// Shows another page of /users, fetching the names again so they stay current
+    async fn handle_names_page(
+        &self,
+        ctx: &Context,
+        component: MessageComponentInteraction,
+        page: usize,
+    ) {
+        if let Err(e) = component
+            .create_interaction_response(&ctx.http, |w| {
+                w.kind(InteractionResponseType::DeferredUpdateMessage)
+            })
+            .await
+        {
+            println!("LOG: Could not respond to discord interaction: {e}");
+            return;
+        }
+
+        let names = self.ask_irc(|reply| IrcRequest::Names { reply }).await;
+        if let Err(e) = component
+            .edit_original_interaction_response(&ctx.http, |w| {
+                names_response(w, &self.config.irc_channel, names, page)
+            })
+            .await
+        {
+            println!("LOG: Could not send names to discord: {e}");
+        }
+    }
+
+    async fn handle_unlink_command(&self, ctx: &Context, command: ApplicationCommandInteraction) {
+        let content = match self.users.unlink_discord(command.user.id.0).await {
+            Ok(true) => "Your discord account is no longer linked to irc".to_string(),
+            Ok(false) => "Your discord account is not linked to irc".to_string(),
+            Err(e) => format!("Could not unlink your account: {e}"),
+        };
+        reply_ephemeral(ctx, &command, content).await;
+    }
+
+    async fn handle_opt_out_command(
+        &self,
+        ctx: &Context,
+        command: ApplicationCommandInteraction,
+        opt_out: bool,
+    ) {
+        let id = command.user.id.0;
+        let result = if opt_out {
+            self.opt_outs.opt_out_discord(id).await
+        } else {
+            self.opt_outs.opt_in_discord(id).await
+        };
+        let content = match (opt_out, result) {
+            (true, Ok(true)) => {
+                "Your messages will no longer be bridged to irc, use /optin to undo this"
+            }
+            (true, Ok(false)) => "You have already opted out of the bridge",
+            (false, Ok(true)) => "Your messages will be bridged to irc again",
+            (false, Ok(false)) => "You had not opted out of the bridge",
+            (_, Err(e)) => {
+                println!("LOG: Could not change opt-out for {id}: {e}");
+                "Could not change your opt-out, try again later"
+            }
+        };
+        reply_ephemeral(ctx, &command, content.to_string()).await;
+    }
+
+    /// Whether a message may be bridged, telling its author why not the first time it is not
+    async fn may_bridge(&self, ctx: &Context, message: &Message) -> bool {
+        let status = self
+            .opt_outs
+            .discord_status(message.author.id.0)
+            .await
+            .map_err(|e| e.to_string());
+        match status {
+            Ok(OptOutStatus::Bridged) => true,
+            Ok(OptOutStatus::OptedOut { notify: false }) => false,
+            Ok(OptOutStatus::OptedOut { notify: true }) => {
+                let notice = format!(
+                    "You opted out of the irc bridge, so your messages in <#{}> are not sent to \
+                     {}. Use /optin to have them bridged again.",
+                    self.config.discord_channel, self.config.irc_channel
+                );
+                if let Err(e) = message
+                    .author
+                    .direct_message(&ctx.http, |m| m.content(notice))
+                    .await
+                {
+                    println!(
+                        "LOG: Could not tell {} they opted out: {e}",
+                        message.author.tag()
+                    );
+                }
+                false
+            }
+            Err(e) => {
+                println!(
+                    "LOG: Could not check opt-out for {}: {e}",
+                    message.author.tag()
+                );
+                false
+            }
+        }
+    }
+
+    async fn handle_whois_command(
+        &self,
+        ctx: &Context,
+        target: String,
+        command: ApplicationCommandInteraction,
+    ) {
+        if let Err(e) = command
+            .create_interaction_response(&ctx.http, |w| {
+                w.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|w| w.ephemeral(true))
+            })
+            .await
+        {
+            println!("LOG: Could not respond to discord interaction: {e}");
+            return;
+        }
+
+        let result = self.whois(&target).await;
+        if let Err(e) = command
+            .edit_original_interaction_response(&ctx.http, |w| match result {
+                Ok(Some((whois, link))) => {
+                    w.embed(|e| whois_embed(e, &whois, link.as_ref(), self.config.mask_whois_hosts))
+                }
+                Ok(None) => w.content(format!("No irc user or link found for {target}")),
+                Err(e) => w.content(e),
+            })
+            .await
+        {
+            println!("LOG: Could not send whois to discord: {e}");
+        }
+    }
+
+    /// Looks up an irc nick, or the nick linked to a discord user, on the irc server. Users who
+    /// are not online are still answered with just their link.
+    async fn whois(
+        &self,
+        target: &str,
+    ) -> std::result::Result<Option<(Whois, Option<StoredUser>)>, String> {
+        let link = linking::find_link(self.users.as_ref(), target)
+            .await
+            .map_err(|e| format!("Could not look up {target}: {e}"))?;
+        let nick = match (target.starts_with('@') || target.starts_with("<@"), &link) {
+            (false, _) => target.to_string(),
+            (true, Some(link)) => link.irc_nick.clone(),
+            (true, None) => return Ok(None),
+        };
+
+        let whois = self
+            .ask_irc(|reply| IrcRequest::Whois {
+                nick: nick.clone(),
+                reply,
+            })
+            .await?;
+        let Some(whois) = whois else {
+            return Ok(link.map(|link| {
+                let offline = Whois {
+                    nick,
+                    ..Default::default()
+                };
+                (offline, Some(link))
+            }));
+        };
+
+        // Prefer the link verified for their account, like messages from them do
+        let link = self
+            .users
+            .find_irc_user(&whois.nick, whois.account.as_deref())
+            .await
+            .map_err(|e| format!("Could not look up {}: {e}", whois.nick))?;
+        Ok(Some((whois, link)))
+    }
+
+    async fn handle_msg_command(
         &self,
         ctx: &Context,
-        member: Option<Member>,
-        user: User,
         nick: String,
+        text: String,
         command: ApplicationCommandInteraction,
     ) {
-        if let Ok(_) = sqlx::query!("SELECT * FROM users WHERE ircnick=?1", nick)
-            .fetch_one(&self.database_pool)
+        if nick.is_empty()
+            || nick.starts_with(['#', '&'])
+            || nick.contains([' ', ',', '!', '@', '*', '?'])
+        {
+            reply_ephemeral(ctx, &command, format!("{nick} is not an irc nick")).await;
+            return;
+        }
+
+        if let Err(e) = command
+            .create_interaction_response(&ctx.http, |w| {
+                w.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|w| w.ephemeral(true))
+            })
             .await
         {
-            let user_id_str = user.id.0.to_string();
-            sqlx::query!(
-                "UPDATE users SET discordid = ?1, discordnick = ?2 WHERE ircnick = ?3",
-                user_id_str,
-                user.name,
-                nick
-            )
-            .execute(&self.database_pool)
+            println!("LOG: Could not respond to discord interaction: {e}");
+            return;
+        }
+
+        let content = match self.send_private_message(&command, &nick, &text).await {
+            Ok(nick) => format!(
+                "Sent to {nick}. Their replies will come to you as direct messages from me, and \
+                 you can answer them there, until the conversation is quiet for {} minutes",
+                self.config.private_message_expiry / 60
+            ),
+            Err(e) => e,
+        };
+        if let Err(e) = command
+            .edit_original_interaction_response(&ctx.http, |w| w.content(content))
             .await
-            .unwrap();
-        } else {
-            let user_id_str = user.id.0.to_string();
-            let name = if let Some(member) = member.clone() {
-                member.nick.unwrap_or(user.name.clone())
-            } else {
-                user.name.clone()
-            };
-            let discordname = user.name.clone();
-            println!("added {}", name);
-            sqlx::query!(
-                            "INSERT INTO users (ircnick, discordid, discordname, discordnick, verified) VALUES (?1,?2,?3,?4,?5)",
-                            nick,
-                            discordname,
-                            name,
-                            user_id_str,
-                            false
-                        ).execute(&self.database_pool).await.expect("Could not insert record into table");
+        {
+            println!("LOG: Could not respond to discord interaction: {e}");
         }
+    }
+
+    /// Sends a private message to someone on irc and routes their replies to the sender. Returns
+    /// the nick as the server spells it.
+    async fn send_private_message(
+        &self,
+        command: &ApplicationCommandInteraction,
+        nick: &str,
+        text: &str,
+    ) -> std::result::Result<String, String> {
+        // Check they are online, the server would only tell us otherwise after the message is gone
+        let whois = self
+            .ask_irc(|reply| IrcRequest::Whois {
+                nick: nick.to_string(),
+                reply,
+            })
+            .await?
+            .ok_or(format!("{nick} is not on irc"))?;
+
+        let name = match &command.member {
+            Some(member) => member.display_name().into_owned(),
+            None => command.user.name.clone(),
+        };
+        self.send_routed_message(&whois.nick, command.user.id.0, &name, text)
+            .await?;
+        Ok(whois.nick)
+    }
+
+    /// Relays a direct message to the bot to the irc nick the author last talked to
+    async fn handle_private_reply(&self, ctx: &Context, message: Message) {
+        let max_idle = Duration::from_secs(self.config.private_message_expiry);
+        let route = self
+            .users
+            .find_private_route_by_discord_id(message.author.id.0, max_idle)
+            .await
+            .map_err(|e| format!("Could not look up your irc conversation: {e}"));
+
+        let error = match route {
+            Ok(Some(nick)) => self
+                .send_routed_message(
+                    &nick,
+                    message.author.id.0,
+                    &message.author.name,
+                    &message.content,
+                )
+                .await
+                .err(),
+            Ok(None) => Some(
+                "You have no open irc conversation, start one with /msg in the server".to_string(),
+            ),
+            Err(e) => Some(e),
+        };
+
+        if let Some(error) = error
+            && let Err(e) = message.channel_id.say(&ctx.http, error).await
+        {
+            println!("LOG: Could not answer direct message: {e}");
+        }
+    }
+
+    /// Ignored and opted out Discord users cannot reach irc privately either
+    fn may_message_irc(&self, discord_id: u64) -> std::result::Result<(), String> {
+        if self.ignores.is_discord_ignored(discord_id) {
+            return Err("The bridge does not relay your messages".to_string());
+        }
+        match self.opt_outs.discord_opted_out(discord_id) {
+            Ok(false) => Ok(()),
+            Ok(true) => Err(
+                "You opted out of the irc bridge. Use /optin to send messages to irc again"
+                    .to_string(),
+            ),
+            Err(e) => Err(format!("Could not check your opt-out: {e}")),
+        }
+    }
+
+    /// Sends a private message to irc for a Discord user and keeps the conversation open
+    async fn send_routed_message(
+        &self,
+        nick: &str,
+        discord_id: u64,
+        name: &str,
+        text: &str,
+    ) -> std::result::Result<(), String> {
+        self.may_message_irc(discord_id)?;
+
+        let max_idle = Duration::from_secs(self.config.private_message_expiry);
+        let remember = |e| format!("Could not keep track of the conversation: {e}");
+        self.users
+            .expire_private_routes(max_idle)
+            .await
+            .map_err(remember)?;
+        self.users
+            .open_private_route(nick, discord_id)
+            .await
+            .map_err(remember)?;
+
+        let text = text.replace(['\r', '\n'], " ");
+        self.senders
+            .irc
+            .send(IrcRequest::SendMessage {
+                to: nick.to_string(),
+                message: irc_sanitise::prefix_lines(nick, &format!("<{name}>"), &text).join("\n"),
+            })
+            .await
+            .map_err(|e| format!("Could not reach the irc side: {e}"))
+    }
+
+    async fn handle_bridge_command(&self, ctx: &Context, command: ApplicationCommandInteraction) {
+        let Some(subcommand) = command.data.options.first() else {
+            return;
+        };
+        let content = match subcommand.name.as_str() {
+            "ignore" => self.handle_ignore_command(subcommand).await,
+            "reload" => {
+                let ignores = self.ignores.reload().await.map_err(|e| e.to_string());
+                let rules = self.filters.reload().await.map_err(|e| e.to_string());
+                match (ignores, rules) {
+                    (Ok(ignores), Ok(rules)) => {
+                        format!("Reloaded {ignores} ignores and {rules} filter rules")
+                    }
+                    (Err(e), _) => format!("Could not reload ignores: {e}"),
+                    (_, Err(e)) => format!("Could not reload filter rules: {e}"),
+                }
+            }
+            "pause" | "resume" => {
+                let direction = match subcommand.name.as_str() {
+                    "resume" => Direction::Both,
+                    _ => sub_option(subcommand, "direction")
+                        .and_then(|direction| Direction::from_name(&direction))
+                        .unwrap_or(Direction::Paused),
+                };
+                match self.direction.set(direction).await {
+                    Ok(()) => direction.describe().to_string(),
+                    Err(e) => format!("Could not change the bridge direction: {e}"),
+                }
+            }
+            "mentions" => {
+                let policy = sub_option(subcommand, "policy")
+                    .and_then(|policy| MentionPolicy::from_name(&policy))
+                    .unwrap_or(MentionPolicy::Deny);
+                match self.mentions.set(policy).await {
+                    Ok(()) => policy.describe().to_string(),
+                    Err(e) => format!("Could not change which pings are allowed: {e}"),
+                }
+            }
+            "link" => self.handle_force_link_command(&command, subcommand).await,
+            "avatar" => {
+                let nick = sub_option(subcommand, "nick").unwrap_or_default();
+                let url = sub_option(subcommand, "url");
+                match self.users.set_avatar(&nick, url.as_deref()).await {
+                    Ok(()) => format!("Updated the avatar of {nick}"),
+                    Err(e) => format!("Could not update the avatar of {nick}: {e}"),
+                }
+            }
+            _ => return,
+        };
+        reply_ephemeral(ctx, &command, content).await;
+    }
+
+    async fn handle_ignore_command(&self, group: &CommandDataOption) -> String {
+        let Some(subcommand) = group.options.first() else {
+            return "Missing ignore subcommand".to_string();
+        };
+        let kind = sub_option(subcommand, "kind")
+            .and_then(|kind| IgnoreKind::from_name(&kind))
+            .unwrap_or(IgnoreKind::Discord);
+        let value = sub_option(subcommand, "value").unwrap_or_default();
+        let command = match subcommand.name.as_str() {
+            "add" => IgnoreCommand::Add { kind, value },
+            "remove" => IgnoreCommand::Remove { kind, value },
+            _ => IgnoreCommand::List,
+        };
+
+        match self.ignores.handle_command(command).await {
+            Ok(lines) => lines.join("\n"),
+            Err(e) => format!("Could not update ignores: {e}"),
+        }
+    }
+
+    async fn handle_force_link_command(
+        &self,
+        command: &ApplicationCommandInteraction,
+        subcommand: &CommandDataOption,
+    ) -> String {
+        let nick = sub_option(subcommand, "nick").unwrap_or_default();
+        let account = sub_option(subcommand, "account");
+        let Some(user) = sub_option(subcommand, "user")
+            .and_then(|id| id.parse().ok())
+            .and_then(|id| command.data.resolved.users.get(&UserId(id)))
+        else {
+            return "Could not find that discord user".to_string();
+        };
+        let display_name = command
+            .data
+            .resolved
+            .members
+            .get(&user.id)
+            .and_then(|member| member.nick.clone())
+            .unwrap_or(user.name.clone());
+
+        let linked = linking::force_link(
+            self.users.as_ref(),
+            &nick,
+            user.id.0,
+            &user.name,
+            &display_name,
+            account.as_deref(),
+        )
+        .await;
+        let name = &user.name;
+        match linked {
+            Ok(()) if account.is_some() => format!("Linked {nick} to {name} and verified it"),
+            Ok(()) => format!("Linked {nick} to {name}, unverified without an account"),
+            Err(e) => format!("Could not link {nick}: {e}"),
+        }
+    }
+
+    async fn handle_links_command(&self, ctx: &Context, command: ApplicationCommandInteraction) {
+        let content = match self.users.all_linked().await {
+            Ok(links) if links.is_empty() => "There are no linked users".to_string(),
+            Ok(links) => links
+                .iter()
+                .map(crate::user_store::User::describe)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Err(e) => format!("Could not list links: {e}"),
+        };
+        reply_ephemeral(ctx, &command, content).await;
+    }
+
+    async fn handle_connect_user_command(
+        &self,
+        ctx: &Context,
+        member: Option<Member>,
+        user: User,
+        nick: String,
+        command: ApplicationCommandInteraction,
+    ) {
+        let display_name = match &member {
+            Some(member) => member.nick.clone().unwrap_or(user.name.clone()),
+            None => user.name.clone(),
+        };
+
+        // Only the code is kept for now. The link is written once the code comes back from the
+        // irc side, which proves the same person controls both accounts.
+        let content = match self.users.find_by_nick(&nick).await {
+            Ok(Some(linked)) if linked.verified && linked.discord_id != Some(user.id.0) => {
+                format!("{nick} is already connected to another discord user")
+            }
+            Ok(_) => {
+                let code = self
+                    .pending_links
+                    .create(user.id.0, &user.name, &display_name, &nick);
+                format!(
+                    "To connect to {nick}, log in to NickServ and send `/msg {} connect {code}` \
+                     on irc within {} minutes",
+                    self.nick.current(),
+                    CODE_LIFETIME.as_secs() / 60
+                )
+            }
+            Err(e) => format!("Could not connect to {nick}: {e}"),
+        };
 
         command
             .create_interaction_response(&ctx.http, |w| {
-                w.interaction_response_data(|w| {
-                    w.content(format!("connecting user {nick}")).ephemeral(true)
-                })
+                w.interaction_response_data(|w| w.content(content).ephemeral(true))
             })
             .await
             .expect("Could not respond to discord interaction");
@@ -112,8 +634,17 @@ impl Handler {
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, message: Message) {
         if !self.should_ignore_message(&ctx, &message) {
-            if self.config.discord_channel == message.channel_id.0 {
-                let message = make_irc_message(&self.config, message, &ctx).await;
+            if message.guild_id.is_none() {
+                self.handle_private_reply(&ctx, message).await;
+            } else if self.config.discord_channel == message.channel_id.0
+                && self.direction.get().to_irc()
+                && self.may_bridge(&ctx, &message).await
+            {
+                let Some(message) =
+                    make_irc_message(&self.config, &self.filters, message, &ctx).await
+                else {
+                    return;
+                };
 
                 let request = IrcRequest::SendMessage {
                     to: self.config.irc_channel.clone(),
@@ -129,34 +660,298 @@ impl EventHandler for Handler {
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         match interaction {
-            Interaction::ApplicationCommand(command) => match command.data.name.as_str() {
-                "connect_user" => {
-                    self.handle_connect_user_command(
-                        &ctx,
-                        command.member.clone(),
-                        command.user.clone(),
-                        command
-                            .data
-                            .options
-                            .first()
-                            .unwrap()
-                            .clone()
-                            .value
-                            .unwrap()
-                            .to_string(),
-                        command,
-                    )
-                    .await;
+            Interaction::ApplicationCommand(command) => {
+                if ADMIN_COMMANDS.contains(&command.data.name.as_str())
+                    && !self.check_admin(&ctx, &command).await
+                {
+                    return;
                 }
-                "users" => self.handle_names_command(&ctx, command).await,
 
-                _ => {}
-            },
+                match command.data.name.as_str() {
+                    "connect_user" => {
+                        self.handle_connect_user_command(
+                            &ctx,
+                            command.member.clone(),
+                            command.user.clone(),
+                            command
+                                .data
+                                .options
+                                .first()
+                                .unwrap()
+                                .clone()
+                                .value
+                                .unwrap()
+                                .to_string(),
+                            command,
+                        )
+                        .await;
+                    }
+                    "users" => self.handle_names_command(&ctx, command).await,
+                    "unlink" => self.handle_unlink_command(&ctx, command).await,
+                    "optout" => self.handle_opt_out_command(&ctx, command, true).await,
+                    "optin" => self.handle_opt_out_command(&ctx, command, false).await,
+                    "whois" => {
+                        let target = string_option(&command, "target").unwrap_or_default();
+                        self.handle_whois_command(&ctx, target, command).await
+                    }
+                    "msg" => {
+                        let nick = string_option(&command, "nick").unwrap_or_default();
+                        let text = string_option(&command, "text").unwrap_or_default();
+                        self.handle_msg_command(&ctx, nick, text, command).await
+                    }
+                    "links" => self.handle_links_command(&ctx, command).await,
+                    "bridge" => self.handle_bridge_command(&ctx, command).await,
+
+                    _ => {}
+                }
+            }
+            Interaction::MessageComponent(component) => {
+                let page = component
+                    .data
+                    .custom_id
+                    .strip_prefix("users:")
+                    .and_then(|page| page.parse().ok());
+                if let Some(page) = page {
+                    self.handle_names_page(&ctx, component, page).await
+                }
+            }
             _ => {}
         }
     }
 }
 
+/// Renders a WHOIS answer, a whois without a user being someone who is not on irc right now
+fn whois_embed<'a>(
+    embed: &'a mut CreateEmbed,
+    whois: &Whois,
+    link: Option<&StoredUser>,
+    mask_host: bool,
+) -> &'a mut CreateEmbed {
+    embed.title(escape_markdown(&whois.nick));
+
+    if whois.user.is_empty() {
+        embed.description("Not on irc right now");
+    } else {
+        let host = if mask_host {
+            irc_whois::mask_host(&whois.host)
+        } else {
+            whois.host.clone()
+        };
+        embed
+            .description(escape_markdown(&whois.realname))
+            .field(
+                "Host",
+                escape_markdown(&format!("{}@{host}", whois.user)),
+                true,
+            )
+            .field(
+                "Account",
+                whois
+                    .account
+                    .as_deref()
+                    .map(escape_markdown)
+                    .unwrap_or("Not logged in".to_string()),
+                true,
+            );
+        if let Some(server) = &whois.server {
+            embed.field("Server", escape_markdown(server), true);
+        }
+        if let Some(idle) = whois.idle {
+            embed.field("Idle", format_duration(idle), true);
+        }
+        if !whois.channels.is_empty() {
+            let channels = escape_markdown(&whois.channels.join(" "));
+            let channels = match channels.char_indices().nth(1000) {
+                Some((end, _)) => format!("{}…", &channels[..end]),
+                None => channels,
+            };
+            embed.field("Channels", channels, false);
+        }
+    }
+
+    let discord = match link.and_then(|link| link.discord_id.map(|id| (id, link.verified))) {
+        Some((id, true)) => format!("<@{id}>"),
+        Some((id, false)) => format!("<@{id}> (not verified)"),
+        None => "Not linked".to_string(),
+    };
+    embed.field("Discord", discord, false)
+}
+
+/// Formats a number of seconds as its two largest units, like 3h 12m
+fn format_duration(seconds: u64) -> String {
+    let parts = [
+        (seconds / 86400, "d"),
+        (seconds / 3600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    let first = parts.iter().position(|(value, _)| *value > 0).unwrap_or(3);
+    parts[first..]
+        .iter()
+        .take(2)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits a NAMES list into ops, voiced and other users, without their mode prefixes
+fn group_names(names: Vec<String>) -> Vec<(&'static str, Vec<String>)> {
+    let mut groups = vec![("Ops", vec![]), ("Voice", vec![]), ("Users", vec![])];
+    for name in names {
+        let group = match name.chars().next() {
+            Some('~' | '&' | '@' | '%') => 0,
+            Some('+') => 1,
+            _ => 2,
+        };
+        // With multi-prefix a nick can carry several prefixes, like @+nick
+        let nick = name.trim_start_matches(['~', '&', '@', '%', '+']);
+        groups[group].1.push(nick.to_string());
+    }
+    for (_, nicks) in &mut groups {
+        nicks.sort_by_key(|nick| nick.to_lowercase());
+    }
+    groups
+}
+
+/// Escapes characters in irc nicks that Discord would read as markdown
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Renders one page of /users as an embed with buttons to page through the rest
+fn names_response<'a>(
+    response: &'a mut EditInteractionResponse,
+    channel: &str,
+    names: std::result::Result<Vec<String>, String>,
+    page: usize,
+) -> &'a mut EditInteractionResponse {
+    let names = match names {
+        Ok(names) => names,
+        Err(e) => return response.content(e).set_embeds(vec![]).components(|c| c),
+    };
+
+    let total = names.len();
+    let entries = group_names(names)
+        .into_iter()
+        .flat_map(|(group, nicks)| nicks.into_iter().map(move |nick| (group, nick)))
+        .collect::<Vec<_>>();
+    let pages = entries.len().div_ceil(NAMES_PER_PAGE).max(1);
+    let page = page.min(pages - 1);
+
+    let mut fields: Vec<(&str, Vec<String>)> = vec![];
+    for (group, nick) in entries
+        .iter()
+        .skip(page * NAMES_PER_PAGE)
+        .take(NAMES_PER_PAGE)
+    {
+        match fields.last_mut() {
+            Some((last, nicks)) if last == group => nicks.push(escape_markdown(nick)),
+            _ => fields.push((group, vec![escape_markdown(nick)])),
+        }
+    }
+
+    response
+        .content("")
+        .embed(|e| {
+            e.title(format!("{total} users in {channel}"))
+                .fields(
+                    fields
+                        .into_iter()
+                        .map(|(group, nicks)| (group, nicks.join(", "), false)),
+                )
+                .footer(|f| f.text(format!("Page {}/{pages}", page + 1)))
+        })
+        .components(|c| {
+            if pages > 1 {
+                c.create_action_row(|row| {
+                    row.create_button(|b| {
+                        b.style(ButtonStyle::Secondary)
+                            .label("Previous")
+                            .custom_id(format!("users:{}", page.saturating_sub(1)))
+                            .disabled(page == 0)
+                    })
+                    .create_button(|b| {
+                        b.style(ButtonStyle::Secondary)
+                            .label("Next")
+                            .custom_id(format!("users:{}", page + 1))
+                            .disabled(page + 1 == pages)
+                    })
+                });
+            }
+            c
+        })
+}
+
+/// The value of a string option of a slash command
+fn string_option(command: &ApplicationCommandInteraction, name: &str) -> Option<String> {
+    command
+        .data
+        .options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| option.value.as_ref())
+        .and_then(|value| value.as_str())
+        .map(String::from)
+}
+
+/// The value of a string option of a subcommand
+fn sub_option(subcommand: &CommandDataOption, name: &str) -> Option<String> {
+    subcommand
+        .options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| option.value.as_ref())
+        .and_then(|value| value.as_str())
+        .map(String::from)
+}
+
+/// The command with its options roughly as the user typed it, for the audit log
+fn command_line(command: &ApplicationCommandInteraction) -> String {
+    fn push_options(line: &mut String, options: &[CommandDataOption]) {
+        for option in options {
+            match &option.value {
+                Some(serenity::json::Value::String(value)) => {
+                    line.push_str(&format!(" {}:{value}", option.name))
+                }
+                Some(value) => line.push_str(&format!(" {}:{value}", option.name)),
+                None => {
+                    line.push_str(&format!(" {}", option.name));
+                    push_options(line, &option.options);
+                }
+            }
+        }
+    }
+
+    let mut line = format!("/{}", command.data.name);
+    push_options(&mut line, &command.data.options);
+    line
+}
+
+/// Answers a slash command with a message only the caller can see, cut down to Discord's message
+/// length limit
+async fn reply_ephemeral(ctx: &Context, command: &ApplicationCommandInteraction, content: String) {
+    let content = match content.char_indices().nth(1990) {
+        Some((end, _)) => format!("{}\n…", &content[..end]),
+        None => content,
+    };
+
+    if let Err(e) = command
+        .create_interaction_response(&ctx.http, |w| {
+            w.interaction_response_data(|w| w.content(content).ephemeral(true))
+        })
+        .await
+    {
+        println!("LOG: Could not respond to discord interaction: {e}");
+    }
+}
+
 async fn get_nick_from_user(user: &User, id: GuildId, ctx: &Context) -> String {
     match user.nick_in(ctx.http.clone(), id).await {
         Some(nick) => nick,
@@ -189,7 +984,13 @@ async fn find_discord_usernames(
     }
 }
 
-async fn make_irc_message(config: &Config, message: Message, ctx: &Context) -> String {
+/// Formats a Discord message for irc, or None if a filter rule dropped it
+async fn make_irc_message(
+    config: &Config,
+    filters: &Filters,
+    message: Message,
+    ctx: &Context,
+) -> Option<String> {
     let nick = get_nick_from_user(
         &message.author,
         message.guild_id.expect("Message must be sent in a channel"),
@@ -213,7 +1014,23 @@ async fn make_irc_message(config: &Config, message: Message, ctx: &Context) -> S
 
     finder.await.unwrap();
 
-    format!("<{}> {}", nick, message_before_replacement.content)
+    let content = match filters.apply(Direction::DiscordToIrc, &message_before_replacement.content)
+    {
+        Filtered::Drop { rule } => {
+            println!("LOG: Filter rule {rule} dropped a message from {nick}");
+            return None;
+        }
+        Filtered::Relay { text, flagged } => {
+            if !flagged.is_empty() {
+                filters::flag_to_admin_users(&ctx.http, config, &flagged, &nick, &text).await;
+            }
+            text
+        }
+    };
+
+    // Every line gets the nick, since each one is sent to irc on its own
+    let nick = irc_format::format_nick(config, &nick, message_before_replacement.author.id.0);
+    Some(irc_sanitise::prefix_lines(&config.irc_channel, &nick, &content).join("\n"))
 }
 
 fn async_regex_replace_usernames(
@@ -0,0 +1,172 @@
+//! Loop prevention. Messages from the bridge itself, from its alternate nicks and from other relay
+//! bots named in the config are never bridged, and neither is anything that repeats a line the
+//! bridge sent to that side a moment ago, which is how another relay echoing us back shows up.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::irc_nick::{alternate_nicks, nick_eq};
+use crate::Config;
+
+/// How long a line the bridge sent counts as something that could come back as an echo
+const ECHO_WINDOW: Duration = Duration::from_secs(30);
+
+/// Whether an irc nick belongs to this bridge or to another relay bot
+pub fn is_relay_nick(config: &Config, nick: &str) -> bool {
+    nick_eq(nick, &config.irc_nick)
+        || alternate_nicks(config)
+            .iter()
+            .chain(&config.irc_relay_bots)
+            .any(|relay| nick_eq(nick, relay))
+}
+
+type Sent = VecDeque<(Instant, String)>;
+
+/// The lines recently sent to each side
+#[derive(Clone)]
+pub struct EchoFilter {
+    window: Duration,
+    to_irc: Arc<Mutex<Sent>>,
+    to_discord: Arc<Mutex<Sent>>,
+}
+
+impl Default for EchoFilter {
+    fn default() -> Self {
+        Self::new(ECHO_WINDOW)
+    }
+}
+
+impl EchoFilter {
+    /// Remembers sent lines for `window`
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            to_irc: Default::default(),
+            to_discord: Default::default(),
+        }
+    }
+
+    pub fn sent_to_irc(&self, text: &str) {
+        self.remember(&self.to_irc, text);
+    }
+
+    pub fn sent_to_discord(&self, text: &str) {
+        self.remember(&self.to_discord, text);
+    }
+
+    /// Relays usually put their own tag in front of what they copy, so a line also counts as an
+    /// echo when it ends with a whole line the bridge sent, nick and all
+    pub fn is_irc_echo(&self, text: &str) -> bool {
+        self.seen(&self.to_irc, text, true)
+    }
+
+    pub fn is_discord_echo(&self, text: &str) -> bool {
+        self.seen(&self.to_discord, text, false)
+    }
+
+    fn expire(&self, sent: &mut Sent) {
+        while sent
+            .front()
+            .is_some_and(|(sent_at, _)| sent_at.elapsed() >= self.window)
+        {
+            sent.pop_front();
+        }
+    }
+
+    /// Messages can be split into lines on the way, so each line is remembered on its own
+    fn remember(&self, sent: &Mutex<Sent>, text: &str) {
+        let mut sent = sent.lock().expect("echo lock poisoned");
+        self.expire(&mut sent);
+        let now = Instant::now();
+        sent.extend(
+            text.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| (now, line.trim().to_string())),
+        );
+    }
+
+    /// Whether every line of the text was sent, after a relay's tag when `tagged`
+    fn seen(&self, sent: &Mutex<Sent>, text: &str, tagged: bool) -> bool {
+        let mut sent = sent.lock().expect("echo lock poisoned");
+        self.expire(&mut sent);
+        let mut lines = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .peekable();
+        lines.peek().is_some()
+            && lines.all(|line| {
+                let line = line.trim();
+                sent.iter().any(|(_, sent)| {
+                    line == sent
+                        || (tagged
+                            && line.strip_suffix(sent.as_str()).is_some_and(|tag| {
+                                tag.ends_with(char::is_whitespace) && !tag.trim().is_empty()
+                            }))
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_sent_are_echoes() {
+        let echoes = EchoFilter::default();
+        echoes.sent_to_irc("<alice> hi");
+        echoes.sent_to_discord("hello");
+
+        assert!(echoes.is_irc_echo("<alice> hi"));
+        assert!(echoes.is_irc_echo("  <alice> hi "));
+        assert!(echoes.is_discord_echo("hello"));
+        assert!(!echoes.is_irc_echo("hello"));
+        assert!(!echoes.is_discord_echo("<alice> hi"));
+        assert!(!echoes.is_irc_echo(""));
+    }
+
+    #[test]
+    fn a_relay_tag_in_front_is_skipped() {
+        let echoes = EchoFilter::default();
+        echoes.sent_to_irc("<alice> hi");
+
+        assert!(echoes.is_irc_echo("[other] <alice> hi"));
+        assert!(!echoes.is_irc_echo("[other] alice> hi"));
+        assert!(!echoes.is_irc_echo("[other]<alice> hi"));
+        assert!(!echoes.is_irc_echo("[other] <alice> hi there"));
+        assert!(!echoes.is_discord_echo("[other] <alice> hi"));
+    }
+
+    #[test]
+    fn the_tag_is_found_with_any_nick_template() {
+        let echoes = EchoFilter::default();
+        echoes.sent_to_irc("[d] alice: hi");
+        echoes.sent_to_irc("bob: hello");
+
+        assert!(echoes.is_irc_echo("<relay> [d] alice: hi"));
+        assert!(echoes.is_irc_echo("[other] bob: hello"));
+        assert!(!echoes.is_irc_echo("alice: hi"));
+        assert!(!echoes.is_irc_echo("<relay> hello"));
+    }
+
+    #[test]
+    fn every_line_has_to_be_an_echo() {
+        let echoes = EchoFilter::default();
+        echoes.sent_to_irc("one\ntwo");
+
+        assert!(echoes.is_irc_echo("two\n\none"));
+        assert!(echoes.is_irc_echo("one"));
+        assert!(!echoes.is_irc_echo("one\nthree"));
+    }
+
+    #[test]
+    fn lines_are_forgotten_after_the_window() {
+        let echoes = EchoFilter::new(Duration::from_millis(20));
+        echoes.sent_to_irc("hi");
+        assert!(echoes.is_irc_echo("hi"));
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!echoes.is_irc_echo("hi"));
+    }
+}
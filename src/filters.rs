@@ -0,0 +1,347 @@
+//! Regex rules applied to every relayed message, loaded from a TOML file and reloaded with the
+//! reload command. A rule can drop the message, replace or mask what it matches, or let the
+//! message through and flag it. Flagged messages are sent by direct message to the Discord users
+//! in admin_discord_users only, not to admins by role or on irc. Rules can be limited to one
+//! direction and to one channel pair; ones for other pairs are skipped.
+//!
+//! ```toml
+//! [[rule]]
+//! name = "invites"
+//! pattern = "discord\\.gg/\\w+"
+//! action = "drop"
+//! direction = "irc-to-discord"
+//! ```
+
+use std::sync::{Arc, RwLock};
+
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serenity::http::Http;
+use serenity::model::prelude::UserId;
+
+use crate::direction::Direction;
+use crate::irc_nick::nick_eq;
+use crate::{Config, Result};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Action {
+    Drop,
+    Replace,
+    Mask,
+    /// Relayed, and sent to the admin Discord users
+    Flag,
+}
+
+/// A rule as written in the rules file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    name: Option<String>,
+    pattern: String,
+    action: Action,
+    replacement: Option<String>,
+    /// both, irc-to-discord or discord-to-irc
+    direction: Option<String>,
+    irc_channel: Option<String>,
+    discord_channel: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleEntry>,
+}
+
+struct Rule {
+    name: String,
+    regex: Regex,
+    action: Action,
+    replacement: String,
+    direction: Direction,
+}
+
+impl Rule {
+    fn new(entry: RuleEntry) -> std::result::Result<Self, String> {
+        let name = entry.name.unwrap_or(entry.pattern.clone());
+        let regex = Regex::new(&entry.pattern).map_err(|e| format!("rule {name}: {e}"))?;
+        let direction = match entry.direction.as_deref() {
+            None => Direction::Both,
+            Some(direction) => Direction::from_name(direction)
+                .filter(|direction| *direction != Direction::Paused)
+                .ok_or(format!(
+                    "rule {name}: unknown direction {direction}, use both, irc-to-discord or \
+                     discord-to-irc"
+                ))?,
+        };
+        let replacement = match (entry.action, entry.replacement) {
+            (Action::Replace, None) => {
+                return Err(format!("rule {name}: replace needs a replacement"))
+            }
+            (_, replacement) => replacement.unwrap_or_default(),
+        };
+
+        Ok(Self {
+            name,
+            regex,
+            action: entry.action,
+            replacement,
+            direction,
+        })
+    }
+}
+
+/// What the rules made of a message
+#[derive(Debug, PartialEq, Eq)]
+pub enum Filtered {
+    /// Not relayed, because of the named rule
+    Drop { rule: String },
+    /// Relayed as `text`, and the named flag rules matched
+    Relay { text: String, flagged: Vec<String> },
+}
+
+#[derive(Clone)]
+pub struct Filters {
+    path: Option<String>,
+    irc_channel: String,
+    discord_channel: u64,
+    rules: Arc<RwLock<Vec<Rule>>>,
+}
+
+impl Filters {
+    pub async fn load(config: &Config) -> Result<Self> {
+        let filters = Self {
+            path: config.filter_rules.clone(),
+            irc_channel: config.irc_channel.clone(),
+            discord_channel: config.discord_channel,
+            rules: Default::default(),
+        };
+        let count = filters.reload().await?;
+        if count > 0 {
+            println!("LOG: Loaded {count} filter rules");
+        }
+        Ok(filters)
+    }
+
+    /// Reads the rules file again. If it has a mistake the rules in use are kept.
+    pub async fn reload(&self) -> Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        let rules = self.parse(&tokio::fs::read_to_string(path).await?)?;
+        let count = rules.len();
+        *self.rules.write().expect("filter lock poisoned") = rules;
+        Ok(count)
+    }
+
+    /// The rules in a rules file that are for this channel pair
+    fn parse(&self, text: &str) -> Result<Vec<Rule>> {
+        let file: RulesFile = toml::from_str(text)?;
+        let mut rules = vec![];
+        for entry in file.rules {
+            let for_this_pair = entry
+                .irc_channel
+                .as_ref()
+                .is_none_or(|channel| nick_eq(channel, &self.irc_channel))
+                && entry
+                    .discord_channel
+                    .is_none_or(|channel| channel == self.discord_channel);
+            if for_this_pair {
+                rules.push(Rule::new(entry)?);
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Runs the rules for messages going one way, `Direction::IrcToDiscord` or
+    /// `Direction::DiscordToIrc`, in the order they are in the file
+    pub fn apply(&self, flow: Direction, text: &str) -> Filtered {
+        let rules = self.rules.read().expect("filter lock poisoned");
+        let mut text = text.to_string();
+        let mut flagged = vec![];
+
+        for rule in rules.iter() {
+            if !(rule.direction == Direction::Both || rule.direction == flow)
+                || !rule.regex.is_match(&text)
+            {
+                continue;
+            }
+            match rule.action {
+                Action::Drop => {
+                    return Filtered::Drop {
+                        rule: rule.name.clone(),
+                    };
+                }
+                Action::Replace => {
+                    text = rule
+                        .regex
+                        .replace_all(&text, rule.replacement.as_str())
+                        .into_owned();
+                }
+                Action::Mask => {
+                    // A bare * is markdown on Discord
+                    let star = if flow == Direction::IrcToDiscord {
+                        "\\*"
+                    } else {
+                        "*"
+                    };
+                    text = rule
+                        .regex
+                        .replace_all(&text, |captures: &Captures| {
+                            star.repeat(captures[0].chars().count())
+                        })
+                        .into_owned();
+                }
+                Action::Flag => flagged.push(rule.name.clone()),
+            }
+        }
+        Filtered::Relay { text, flagged }
+    }
+}
+
+/// Sends a message a flag rule matched to each of the admin_discord_users. It is only logged if
+/// they cannot be reached.
+pub async fn flag_to_admin_users(
+    http: &Http,
+    config: &Config,
+    rules: &[String],
+    from: &str,
+    text: &str,
+) {
+    let report = format!(
+        "Filter rule {} flagged a message from {from}: {text}",
+        rules.join(", ")
+    );
+    println!("LOG: {report}");
+
+    for admin in &config.admin_discord_users {
+        let result = match UserId(*admin).create_dm_channel(http).await {
+            Ok(channel) => channel.say(http, &report).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            println!("LOG: Could not send flagged message to admin {admin}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(rules: &str) -> Filters {
+        let filters = Filters {
+            path: None,
+            irc_channel: "#Bridge".to_string(),
+            discord_channel: 1234,
+            rules: Default::default(),
+        };
+        let rules = filters.parse(rules).unwrap();
+        *filters.rules.write().unwrap() = rules;
+        filters
+    }
+
+    fn relayed(text: &str) -> Filtered {
+        Filtered::Relay {
+            text: text.to_string(),
+            flagged: vec![],
+        }
+    }
+
+    #[test]
+    fn drop_rules_only_apply_their_way() {
+        let filters = filters(
+            r##"
+            [[rule]]
+            name = "invites"
+            pattern = "discord\\.gg/\\w+"
+            action = "drop"
+            direction = "irc-to-discord"
+            "##,
+        );
+        let invite = "join discord.gg/abc";
+        assert_eq!(
+            filters.apply(Direction::IrcToDiscord, invite),
+            Filtered::Drop {
+                rule: "invites".to_string()
+            }
+        );
+        assert_eq!(
+            filters.apply(Direction::DiscordToIrc, invite),
+            relayed(invite)
+        );
+    }
+
+    #[test]
+    fn rules_run_in_order() {
+        let filters = filters(
+            r##"
+            [[rule]]
+            pattern = "http://wiki\\.internal/(\\w+)"
+            action = "replace"
+            replacement = "https://wiki.example.com/$1"
+
+            [[rule]]
+            pattern = "(?i)darn"
+            action = "mask"
+
+            [[rule]]
+            name = "wiki"
+            pattern = "wiki"
+            action = "flag"
+            "##,
+        );
+        assert_eq!(
+            filters.apply(
+                Direction::DiscordToIrc,
+                "Darn, see http://wiki.internal/Page"
+            ),
+            Filtered::Relay {
+                text: "****, see https://wiki.example.com/Page".to_string(),
+                flagged: vec!["wiki".to_string()],
+            }
+        );
+        assert_eq!(
+            filters.apply(Direction::IrcToDiscord, "darn"),
+            relayed("\\*\\*\\*\\*")
+        );
+    }
+
+    #[test]
+    fn rules_for_other_pairs_are_skipped() {
+        let filters = filters(
+            r##"
+            [[rule]]
+            pattern = "a"
+            action = "drop"
+            irc_channel = "#other"
+
+            [[rule]]
+            pattern = "b"
+            action = "drop"
+            irc_channel = "#bridge"
+            discord_channel = 1234
+            "##,
+        );
+        assert_eq!(filters.apply(Direction::IrcToDiscord, "a"), relayed("a"));
+        assert!(matches!(
+            filters.apply(Direction::IrcToDiscord, "b"),
+            Filtered::Drop { .. }
+        ));
+    }
+
+    #[test]
+    fn mistakes_in_rules_are_refused() {
+        let filters = filters("");
+        for rules in [
+            "[[rule]]\npattern = \"(\"\naction = \"drop\"",
+            "[[rule]]\npattern = \"a\"\naction = \"replace\"",
+            "[[rule]]\npattern = \"a\"\naction = \"drop\"\ndirection = \"paused\"",
+            "[[rule]]\npattern = \"a\"\naction = \"explode\"",
+            "[[rule]]\npattern = \"a\"\naction = \"flag-to-admins\"",
+            "[[rule]]\npattern = \"a\"\naction = \"drop\"\ntypo = 1",
+        ] {
+            assert!(filters.parse(rules).is_err(), "{rules}");
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! People whose messages are not bridged. Ignores added at runtime are stored in the database and
+//! kept in memory, so they apply straight away; the ones from the config are always in place.
+
+use std::sync::{Arc, RwLock};
+
+use clap::{Subcommand, ValueEnum};
+
+use crate::irc_nick::fold_nick;
+use crate::user_store::UserStore;
+use crate::{Config, Result};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IgnoreKind {
+    /// A Discord user ID
+    Discord,
+    /// An irc nick
+    Nick,
+    /// An irc nick!user@host glob, with * and ?
+    Hostmask,
+    /// An irc services account
+    Account,
+}
+
+impl IgnoreKind {
+    pub const ALL: [IgnoreKind; 4] = [
+        IgnoreKind::Discord,
+        IgnoreKind::Nick,
+        IgnoreKind::Hostmask,
+        IgnoreKind::Account,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IgnoreKind::Discord => "discord",
+            IgnoreKind::Nick => "nick",
+            IgnoreKind::Hostmask => "hostmask",
+            IgnoreKind::Account => "account",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+/// The ignore commands admins can use on both sides
+#[derive(Subcommand, Clone, Debug)]
+pub enum IgnoreCommand {
+    Add { kind: IgnoreKind, value: String },
+    Remove { kind: IgnoreKind, value: String },
+    List,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ignore {
+    pub kind: IgnoreKind,
+    pub value: String,
+}
+
+impl Ignore {
+    /// Checks and normalises a value, so the same ignore is always stored the same way. Discord
+    /// users may be given as a mention.
+    pub fn new(kind: IgnoreKind, value: &str) -> std::result::Result<Self, String> {
+        let value = value.trim();
+        let value = match kind {
+            IgnoreKind::Discord => value
+                .trim_start_matches("<@")
+                .trim_start_matches('!')
+                .trim_end_matches('>')
+                .parse::<u64>()
+                .map_err(|_| format!("{value} is not a discord user id"))?
+                .to_string(),
+            IgnoreKind::Hostmask if !(value.contains('!') && value.contains('@')) => {
+                return Err(format!("{value} is not a nick!user@host mask"));
+            }
+            _ if value.is_empty() || value.contains(' ') => {
+                return Err(format!("{value:?} is not a valid {}", kind.name()));
+            }
+            _ => fold_nick(value),
+        };
+        Ok(Self { kind, value })
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} {}", self.kind.name(), self.value)
+    }
+}
+
+#[derive(Clone)]
+pub struct IgnoreList {
+    store: Arc<dyn UserStore>,
+    from_config: Arc<Vec<Ignore>>,
+    ignores: Arc<RwLock<Vec<Ignore>>>,
+}
+
+impl IgnoreList {
+    pub async fn load(store: Arc<dyn UserStore>, config: &Config) -> Result<Self> {
+        let mut from_config = vec![];
+        for id in &config.ignored_discord_users {
+            from_config.push(Ignore::new(IgnoreKind::Discord, &id.to_string())?);
+        }
+        // Config entries for irc have always matched both nicks and accounts
+        for name in &config.ignored_irc_users {
+            from_config.push(Ignore::new(IgnoreKind::Nick, name)?);
+            from_config.push(Ignore::new(IgnoreKind::Account, name)?);
+        }
+
+        let ignores = store.all_ignores().await?;
+        println!(
+            "LOG: Loaded {} ignores, {} more from config",
+            ignores.len(),
+            from_config.len()
+        );
+
+        Ok(Self {
+            store,
+            from_config: Arc::new(from_config),
+            ignores: Arc::new(RwLock::new(ignores)),
+        })
+    }
+
+    fn any(&self, matches: impl Fn(&Ignore) -> bool) -> bool {
+        self.from_config.iter().any(&matches)
+            || self
+                .ignores
+                .read()
+                .expect("ignore lock poisoned")
+                .iter()
+                .any(matches)
+    }
+
+    pub fn is_discord_ignored(&self, discord_id: u64) -> bool {
+        let id = discord_id.to_string();
+        self.any(|ignore| ignore.kind == IgnoreKind::Discord && ignore.value == id)
+    }
+
+    pub fn is_irc_ignored(
+        &self,
+        nick: &str,
+        hostmask: Option<&str>,
+        account: Option<&str>,
+    ) -> bool {
+        let nick = fold_nick(nick);
+        let hostmask = hostmask.map(fold_nick);
+        let account = account.map(fold_nick);
+        self.any(|ignore| match ignore.kind {
+            IgnoreKind::Discord => false,
+            IgnoreKind::Nick => ignore.value == nick,
+            IgnoreKind::Hostmask => hostmask
+                .as_ref()
+                .is_some_and(|hostmask| glob_match(&ignore.value, hostmask)),
+            IgnoreKind::Account => account.as_ref() == Some(&ignore.value),
+        })
+    }
+
+    /// Picks up ignores changed in the database by something else
+    pub async fn reload(&self) -> Result<usize> {
+        let ignores = self.store.all_ignores().await?;
+        let count = ignores.len();
+        *self.ignores.write().expect("ignore lock poisoned") = ignores;
+        Ok(count)
+    }
+
+    /// Returns false if the ignore was already there
+    pub async fn add(&self, ignore: Ignore) -> Result<bool> {
+        if !self.store.add_ignore(&ignore).await? {
+            return Ok(false);
+        }
+        self.ignores
+            .write()
+            .expect("ignore lock poisoned")
+            .push(ignore);
+        Ok(true)
+    }
+
+    /// Returns false if there was no such ignore. Ignores from the config cannot be removed.
+    pub async fn remove(&self, ignore: &Ignore) -> Result<bool> {
+        if !self.store.remove_ignore(ignore).await? {
+            return Ok(false);
+        }
+        self.ignores
+            .write()
+            .expect("ignore lock poisoned")
+            .retain(|existing| existing != ignore);
+        Ok(true)
+    }
+
+    /// Every ignore, one per line, marking the ones that come from the config
+    pub fn describe(&self) -> Vec<String> {
+        let ignores = self.ignores.read().expect("ignore lock poisoned");
+        self.from_config
+            .iter()
+            .map(|ignore| format!("{} (from config)", ignore.describe()))
+            .chain(ignores.iter().map(Ignore::describe))
+            .collect()
+    }
+
+    /// Runs an ignore command, returning the lines to answer with
+    pub async fn handle_command(&self, command: IgnoreCommand) -> Result<Vec<String>> {
+        let reply = match command {
+            IgnoreCommand::Add { kind, value } => match Ignore::new(kind, &value) {
+                Ok(ignore) if self.add(ignore.clone()).await? => {
+                    println!("LOG: Ignoring {}", ignore.describe());
+                    format!("Now ignoring {}", ignore.describe())
+                }
+                Ok(ignore) => format!("Already ignoring {}", ignore.describe()),
+                Err(e) => e,
+            },
+            IgnoreCommand::Remove { kind, value } => match Ignore::new(kind, &value) {
+                Ok(ignore) if self.remove(&ignore).await? => {
+                    println!("LOG: No longer ignoring {}", ignore.describe());
+                    format!("No longer ignoring {}", ignore.describe())
+                }
+                Ok(ignore) => format!(
+                    "{} is not ignored, or is ignored in the config",
+                    ignore.describe()
+                ),
+                Err(e) => e,
+            },
+            IgnoreCommand::List => {
+                let ignores = self.describe();
+                if ignores.is_empty() {
+                    return Ok(vec!["Nobody is ignored".to_string()]);
+                }
+                return Ok(ignores);
+            }
+        };
+        Ok(vec![reply])
+    }
+}
+
+/// Matches irc style globs, where * is any run of characters and ? any single one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Where to pick up again if the text so far does not match after the last *
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+            }
+            Some(c) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_any_run_or_one_character() {
+        assert!(glob_match("*!*@*.example.com", "troll!~t@host.example.com"));
+        assert!(glob_match("troll!?t@*", "troll!~t@anywhere"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("troll!?t@*", "troll!t@anywhere"));
+        assert!(!glob_match("*!*@*.example.com", "troll!~t@example.org"));
+        assert!(!glob_match("", "troll"));
+    }
+
+    #[test]
+    fn globs_try_every_place_a_star_could_end() {
+        assert!(glob_match("*a*b", "aaab"));
+        assert!(glob_match("*ab", "abab"));
+        assert!(glob_match("a*b*c", "abcbc"));
+        assert!(!glob_match("*ab", "abba"));
+        assert!(!glob_match("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn discord_ignores_take_ids_and_mentions() {
+        for value in ["1234", " 1234 ", "<@1234>", "<@!1234>"] {
+            assert_eq!(
+                Ignore::new(IgnoreKind::Discord, value).unwrap().value,
+                "1234"
+            );
+        }
+        assert!(Ignore::new(IgnoreKind::Discord, "someone").is_err());
+        assert!(Ignore::new(IgnoreKind::Discord, "").is_err());
+    }
+
+    #[test]
+    fn irc_ignores_are_folded() {
+        let ignore = Ignore::new(IgnoreKind::Nick, "Troll[m]").unwrap();
+        assert_eq!(ignore.value, "troll{m}");
+        let ignore = Ignore::new(IgnoreKind::Hostmask, "Troll!*@Host").unwrap();
+        assert_eq!(ignore.value, "troll!*@host");
+    }
+
+    #[test]
+    fn mistakes_in_ignores_are_refused() {
+        assert!(Ignore::new(IgnoreKind::Hostmask, "troll").is_err());
+        assert!(Ignore::new(IgnoreKind::Hostmask, "troll@host").is_err());
+        assert!(Ignore::new(IgnoreKind::Hostmask, "a b!c@d").is_err());
+        assert!(Ignore::new(IgnoreKind::Nick, "").is_err());
+        assert!(Ignore::new(IgnoreKind::Nick, "   ").is_err());
+        assert!(Ignore::new(IgnoreKind::Account, "two words").is_err());
+    }
+}
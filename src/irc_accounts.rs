@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use irc::client::Sender;
+use irc::proto::{Command, Message, Response};
+
+use crate::irc_caps::message_account;
+use crate::irc_nick::fold_nick;
+use crate::Result;
+
+/// Token we put in our WHOX queries so the replies can be told apart from anyone else's WHO
+const WHOX_TOKEN: &str = "152";
+
+/// A change to the services account we know a nick to be logged in to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountChange {
+    pub nick: String,
+    pub account: Option<String>,
+}
+
+/// Keeps track of which services account each nick we can see is logged in to, using
+/// account-tag, account-notify, extended-join and WHOX replies. Nicks are stored folded with
+/// the rfc1459 casemapping.
+#[derive(Clone, Debug)]
+pub struct AccountTracker {
+    accounts: Arc<RwLock<HashMap<String, Option<String>>>>,
+    whox: Arc<RwLock<bool>>,
+    sender: Sender,
+}
+
+impl AccountTracker {
+    pub fn new(sender: Sender) -> Self {
+        Self {
+            accounts: Default::default(),
+            whox: Default::default(),
+            sender,
+        }
+    }
+
+    /// The account the nick is logged in to, `None` if they are not logged in or we do not know
+    pub fn account(&self, nick: &str) -> Option<String> {
+        self.accounts
+            .read()
+            .expect("accounts lock poisoned")
+            .get(&fold_nick(nick))
+            .cloned()
+            .flatten()
+    }
+
+    /// Updates the known accounts from a message, returning the change if it told us something
+    /// new about a nick's account
+    pub fn handle_message(
+        &self,
+        message: &Message,
+        from_us: bool,
+    ) -> Result<Option<AccountChange>> {
+        let source = message.source_nickname();
+
+        match &message.command {
+            Command::Response(Response::RPL_ISUPPORT, args) => {
+                if args.iter().any(|token| token == "WHOX") {
+                    *self.whox.write().expect("accounts lock poisoned") = true;
+                }
+            }
+            // When we join a channel, ask who everyone in it is logged in as
+            Command::JOIN(channel, _, _) if from_us => {
+                if *self.whox.read().expect("accounts lock poisoned") {
+                    self.sender.send(Command::Raw(
+                        "WHO".to_string(),
+                        vec![channel.clone(), format!("%tna,{WHOX_TOKEN}")],
+                    ))?;
+                }
+            }
+            // With extended-join the account is the second parameter, "*" when logged out
+            Command::JOIN(_, Some(account), Some(_)) => {
+                if let Some(nick) = source {
+                    return Ok(self.update(
+                        nick,
+                        Some(account.as_str()).filter(|account| *account != "*"),
+                    ));
+                }
+            }
+            // account-notify tells us when someone logs in or out
+            Command::ACCOUNT(account) => {
+                if let Some(nick) = source {
+                    return Ok(self.update(
+                        nick,
+                        Some(account.as_str()).filter(|account| *account != "*"),
+                    ));
+                }
+            }
+            // WHOX reply to our query: <us> <token> <nick> <account>, account "0" when logged out
+            Command::Raw(numeric, args) if numeric == "354" => {
+                if let [_, token, nick, account] = args.as_slice()
+                    && token == WHOX_TOKEN
+                {
+                    return Ok(self.update(
+                        nick,
+                        Some(account.as_str()).filter(|account| *account != "0"),
+                    ));
+                }
+            }
+            Command::NICK(new_nick) => {
+                if let Some(old_nick) = source {
+                    let mut accounts = self.accounts.write().expect("accounts lock poisoned");
+                    if let Some(account) = accounts.remove(&fold_nick(old_nick)) {
+                        accounts.insert(fold_nick(new_nick), account);
+                    }
+                }
+            }
+            Command::QUIT(_) => {
+                if let Some(nick) = source {
+                    self.accounts
+                        .write()
+                        .expect("accounts lock poisoned")
+                        .remove(&fold_nick(nick));
+                }
+            }
+            _ => {}
+        }
+
+        // Any other message from a user carries their account if account-tag is enabled
+        match (source, message_account(message)) {
+            (Some(nick), Some(account)) => Ok(self.update(nick, Some(account))),
+            _ => Ok(None),
+        }
+    }
+
+    fn update(&self, nick: &str, account: Option<&str>) -> Option<AccountChange> {
+        let mut accounts = self.accounts.write().expect("accounts lock poisoned");
+        let account = account.map(String::from);
+        let previous = accounts.insert(fold_nick(nick), account.clone());
+
+        (previous.as_ref() != Some(&account)).then(|| AccountChange {
+            nick: nick.to_string(),
+            account,
+        })
+    }
+}
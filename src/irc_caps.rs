@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use irc::client::Sender;
+use irc::proto::{CapSubCommand, Command, Message};
+
+use crate::Result;
+
+/// IRCv3 capabilities the bridge knows how to use, requested whenever the server offers them
+pub const WANTED_CAPS: &[&str] = &[
+    "server-time",
+    "message-tags",
+    "echo-message",
+    "account-tag",
+    "account-notify",
+    "extended-join",
+    "labeled-response",
+    "batch",
+];
+
+/// How long we wait for the server to echo one of our messages back before giving up on it
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The capabilities currently enabled on the irc connection
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    enabled: Arc<RwLock<HashSet<String>>>,
+    sender: Sender,
+}
+
+impl Capabilities {
+    pub fn new(sender: Sender) -> Self {
+        Self {
+            enabled: Default::default(),
+            sender,
+        }
+    }
+
+    pub fn has(&self, cap: &str) -> bool {
+        self.enabled
+            .read()
+            .expect("caps lock poisoned")
+            .contains(cap)
+    }
+
+    /// Keeps the enabled set up to date from CAP ACK/DEL, and requests capabilities the server
+    /// starts offering after registration.
+    pub fn handle_message(&self, message: &Message) -> Result<()> {
+        let Command::CAP(_, subcommand, first, second) = &message.command else {
+            return Ok(());
+        };
+        let caps = cap_list(first, second);
+
+        match subcommand {
+            CapSubCommand::ACK => {
+                let mut enabled = self.enabled.write().expect("caps lock poisoned");
+                for cap in caps {
+                    match cap.strip_prefix('-') {
+                        Some(cap) => enabled.remove(cap),
+                        None => enabled.insert(cap.to_string()),
+                    };
+                }
+                println!("LOG: Enabled irc capabilities {enabled:?}");
+            }
+            CapSubCommand::DEL => {
+                let mut enabled = self.enabled.write().expect("caps lock poisoned");
+                for cap in caps {
+                    enabled.remove(cap);
+                }
+            }
+            CapSubCommand::NEW => {
+                let wanted: Vec<&str> = caps
+                    .filter(|cap| WANTED_CAPS.contains(cap) && !self.has(cap))
+                    .collect();
+                if !wanted.is_empty() {
+                    self.sender.send(Command::CAP(
+                        None,
+                        CapSubCommand::REQ,
+                        None,
+                        Some(wanted.join(" ")),
+                    ))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The capability names in a CAP message, without any `=value` parts. Depending on whether a
+/// reply is split over several lines the list ends up in either of the last two parameters.
+pub fn cap_list<'a>(
+    first: &'a Option<String>,
+    second: &'a Option<String>,
+) -> impl Iterator<Item = &'a str> {
+    second
+        .as_ref()
+        .or(first.as_ref())
+        .map(String::as_str)
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|cap| cap.split('=').next().unwrap_or(cap))
+}
+
+pub fn tag<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    message
+        .tags
+        .as_ref()?
+        .iter()
+        .find(|tag| tag.0 == name)
+        .and_then(|tag| tag.1.as_deref())
+}
+
+/// When the server says the message was sent, from the server-time tag
+pub fn message_time(message: &Message) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(tag(message, "time")?)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// Adds a marker with the original time to messages that reach us late, such as after a netsplit
+/// or when played back by a bouncer. Webhooks cannot backdate messages, so this is the best we can
+/// do to keep the original timestamp.
+pub fn mark_delayed(text: String, message: &Message, threshold: Duration) -> String {
+    match message_time(message) {
+        Some(sent)
+            if (Utc::now() - sent)
+                .to_std()
+                .is_ok_and(|delay| delay > threshold) =>
+        {
+            format!("{text} *(delayed, sent <t:{}:f>)*", sent.timestamp())
+        }
+        _ => text,
+    }
+}
+
+/// The services account of the sender, from the account-tag tag
+pub fn message_account(message: &Message) -> Option<&str> {
+    tag(message, "account").filter(|account| *account != "*")
+}
+
+#[derive(Debug)]
+struct PendingSend {
+    label: Option<String>,
+    target: String,
+    text: String,
+    sent_at: Instant,
+}
+
+/// Tracks messages we sent until the server confirms them with echo-message, matching echoes by
+/// label when labeled-response is available and by target and text otherwise.
+#[derive(Clone, Debug)]
+pub struct DeliveryTracker {
+    pending: Arc<Mutex<Vec<PendingSend>>>,
+    next_label: Arc<AtomicU64>,
+    caps: Capabilities,
+    sender: Sender,
+}
+
+impl DeliveryTracker {
+    pub fn new(caps: Capabilities, sender: Sender) -> Self {
+        Self {
+            pending: Default::default(),
+            next_label: Default::default(),
+            caps,
+            sender,
+        }
+    }
+
+    /// Sends a PRIVMSG, labelled if the server supports it, and starts waiting for its echo
+    pub fn send_privmsg(&self, target: String, text: String) -> Result<()> {
+        if !self.caps.has("echo-message") {
+            self.sender.send_privmsg(target, text)?;
+            return Ok(());
+        }
+
+        let label = self
+            .caps
+            .has("labeled-response")
+            .then(|| format!("bridge{}", self.next_label.fetch_add(1, Ordering::Relaxed)));
+        let tags = label
+            .clone()
+            .map(|label| vec![irc::proto::message::Tag("label".to_string(), Some(label))]);
+
+        self.sender.send(Message {
+            tags,
+            prefix: None,
+            command: Command::PRIVMSG(target.clone(), text.clone()),
+        })?;
+
+        let mut pending = self.pending.lock().expect("delivery lock poisoned");
+        expire(&mut pending);
+        pending.push(PendingSend {
+            label,
+            target,
+            text,
+            sent_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Looks for the echo, labelled ACK or labelled error for one of our messages. Returns true
+    /// if the message was one of ours and should not be handled any further.
+    pub fn handle_message(&self, message: &Message, from_us: bool) -> bool {
+        let mut pending = self.pending.lock().expect("delivery lock poisoned");
+        expire(&mut pending);
+
+        let label = tag(message, "label");
+        let position = match (&message.command, label) {
+            (_, Some(label)) => pending
+                .iter()
+                .position(|send| send.label.as_deref() == Some(label)),
+            (Command::PRIVMSG(target, text), None) if from_us => pending.iter().position(|send| {
+                send.label.is_none() && &send.target == target && &send.text == text
+            }),
+            _ => None,
+        };
+        let Some(position) = position else {
+            return false;
+        };
+
+        let send = pending.remove(position);
+        match &message.command {
+            Command::Response(response, args) if response.is_error() => {
+                println!(
+                    "LOG: irc server rejected message to {}: {}",
+                    send.target,
+                    args.last().map(String::as_str).unwrap_or_default()
+                );
+            }
+            _ => println!(
+                "LOG: Delivery to {} confirmed after {:?}",
+                send.target,
+                send.sent_at.elapsed()
+            ),
+        }
+        true
+    }
+}
+
+fn expire(pending: &mut Vec<PendingSend>) {
+    pending.retain(|send| {
+        let waiting = send.sent_at.elapsed() < DELIVERY_TIMEOUT;
+        if !waiting {
+            println!(
+                "LOG: irc server never confirmed message to {}: {}",
+                send.target, send.text
+            );
+        }
+        waiting
+    });
+}
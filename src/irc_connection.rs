@@ -0,0 +1,386 @@
+//! The irc client only knows how to dial the server itself, which leaves no room for a bind
+//! address, a SOCKS5 proxy of our choosing, certificate pinning or accepting invalid
+//! certificates. Instead we open the server connection here and hand the irc client a plaintext
+//! loopback socket that is piped to it. Incoming lines are re-encoded as UTF-8 on the way.
+//!
+//! Any local user could connect to that socket, so the first line has to be a random token only
+//! the bridge knows before the server session is handed over. The relay serves one irc client
+//! connection: when either end closes, the irc stream ends and the bridge exits, to be restarted
+//! by its service manager with a new server connection.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use encoding_rs::Encoding;
+use irc::client::Sender;
+use irc::proto::{Command, Message};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::{Config, Result};
+
+/// SHA-256 fingerprint of the certificate the irc server is expected to present
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint([u8; 32]);
+
+impl FromStr for Fingerprint {
+    type Err = String;
+
+    /// Accepts hex with or without `:` separators, as printed by `openssl x509 -fingerprint`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let hex: String = s.chars().filter(|c| *c != ':').collect();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err("expected a SHA-256 fingerprint of 32 hex encoded bytes".to_string());
+        }
+
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|e| format!("invalid fingerprint: {e}"))?;
+        }
+        Ok(Fingerprint(bytes))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: Vec<String> = self.0.iter().map(|byte| format!("{byte:02X}")).collect();
+        write!(f, "{}", hex.join(":"))
+    }
+}
+
+/// Parses an encoding label such as `utf-8`, `latin1` or `windows-1252`
+pub fn parse_encoding(label: &str) -> std::result::Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding {label}"))
+}
+
+trait Upstream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Upstream for T {}
+
+/// Checks the connection options that only make sense in combination with others
+pub fn validate_config(config: &Config) -> Result<()> {
+    if !config.irc_tls
+        && (config.irc_tls_fingerprint.is_some()
+            || config.irc_tls_accept_invalid_certs
+            || config.irc_client_cert.is_some())
+    {
+        return Err("TLS options are set but BRIDGE_IRC_TLS is disabled".into());
+    }
+    if config.irc_proxy_username.is_some() != config.irc_proxy_password.is_some() {
+        return Err("The SOCKS5 proxy needs both a username and a password, or neither".into());
+    }
+    Ok(())
+}
+
+/// The command the irc client sends the relay token with. It is never passed on to the server.
+const RELAY_AUTH_COMMAND: &str = "BRIDGE-RELAY-AUTH";
+
+/// How long a local connection has to send the relay token before it is dropped
+const RELAY_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The loopback socket the irc client should connect to
+pub struct Relay {
+    pub address: SocketAddr,
+    token: String,
+}
+
+impl Relay {
+    /// Sends the relay token, which must be the first thing the irc client sends
+    pub fn authenticate(&self, sender: &Sender) -> Result<()> {
+        sender.send(Command::Raw(
+            RELAY_AUTH_COMMAND.to_string(),
+            vec![self.token.clone()],
+        ))?;
+        Ok(())
+    }
+}
+
+/// Connects to the irc server and starts the relay the irc client should connect to
+pub async fn connect(config: &Config) -> Result<Relay> {
+    let stream = open_tcp(config).await?;
+    let upstream: Box<dyn Upstream> = if config.irc_tls {
+        Box::new(open_tls(config, stream).await?)
+    } else {
+        Box::new(stream)
+    };
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let address = listener.local_addr()?;
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let encoding = config.irc_encoding;
+    let relay_token = token.clone();
+    tokio::spawn(async move {
+        if let Err(e) = relay(listener, upstream, encoding, &relay_token).await {
+            println!("LOG: irc connection closed: {e}");
+        }
+    });
+
+    Ok(Relay { address, token })
+}
+
+async fn relay(
+    listener: TcpListener,
+    upstream: Box<dyn Upstream>,
+    encoding: &'static Encoding,
+    token: &str,
+) -> std::io::Result<()> {
+    let (mut local_reader, local_writer) = loop {
+        let (local, peer) = listener.accept().await?;
+        match accept_client(local, token).await {
+            Ok(local) => break local,
+            Err(e) => println!("LOG: Refused local irc connection from {peer}: {e}"),
+        }
+    };
+    // Only the irc client is meant to connect, so stop listening as soon as it has
+    drop(listener);
+
+    let (upstream_reader, mut upstream_writer) = tokio::io::split(upstream);
+
+    // Whichever side closes first ends the relay, dropping the other side closes it too
+    tokio::select! {
+        result = tokio::io::copy(&mut local_reader, &mut upstream_writer) => result.map(|_| ()),
+        result = forward_incoming(upstream_reader, local_writer, encoding) => result,
+    }
+}
+
+/// Checks that a local connection starts with the relay token, returning its halves with
+/// whatever it sent after the token still to be read
+async fn accept_client(
+    local: TcpStream,
+    token: &str,
+) -> std::result::Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf), String> {
+    let (reader, writer) = local.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+
+    // An irc line is at most 512 bytes, so nothing longer can be the token
+    let mut first_line = (&mut reader).take(512);
+    tokio::time::timeout(RELAY_AUTH_TIMEOUT, first_line.read_until(b'\n', &mut line))
+        .await
+        .map_err(|_| "no token sent in time".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let expected = format!("{RELAY_AUTH_COMMAND} {token}");
+    if line.trim_ascii_end() != expected.as_bytes() {
+        return Err("wrong relay token".to_string());
+    }
+    Ok((reader, writer))
+}
+
+/// Copies lines from the server to the irc client, decoding anything that is not valid UTF-8
+/// with the configured encoding. Lines the irc client would fail to parse are dropped here,
+/// since a single parse error makes its stream end.
+async fn forward_incoming(
+    upstream: impl AsyncRead + Unpin,
+    mut local: impl AsyncWrite + Unpin,
+    encoding: &'static Encoding,
+) -> std::io::Result<()> {
+    let mut upstream = BufReader::new(upstream);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if upstream.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+
+        let decoded = decode_line(&line, encoding);
+        if decoded.trim_end().is_empty() {
+            continue;
+        }
+        if let Err(e) = decoded.parse::<Message>() {
+            println!("LOG: Dropping malformed irc line {decoded:?}: {e}");
+            continue;
+        }
+
+        local.write_all(decoded.as_bytes()).await?;
+    }
+}
+
+/// Decodes a line as UTF-8, falling back to the configured encoding, with any bytes that are
+/// invalid in that encoding replaced rather than rejected
+fn decode_line<'a>(line: &'a [u8], encoding: &'static Encoding) -> Cow<'a, str> {
+    match std::str::from_utf8(line) {
+        Ok(line) => Cow::Borrowed(line),
+        Err(_) => encoding.decode_without_bom_handling(line).0,
+    }
+}
+
+async fn open_tcp(config: &Config) -> Result<TcpStream> {
+    let Some(proxy_host) = &config.irc_proxy_host else {
+        return dial(&config.irc_host, config.irc_port, config.irc_bind_address).await;
+    };
+
+    println!("LOG: Connecting to irc through SOCKS5 proxy {proxy_host}");
+    let socket = dial(proxy_host, config.irc_proxy_port, config.irc_bind_address).await?;
+    let target = (config.irc_host.as_str(), config.irc_port);
+
+    let stream = match (&config.irc_proxy_username, &config.irc_proxy_password) {
+        (Some(username), Some(password)) => {
+            Socks5Stream::connect_with_password_and_socket(socket, target, username, password)
+                .await?
+        }
+        _ => Socks5Stream::connect_with_socket(socket, target).await?,
+    };
+
+    Ok(stream.into_inner())
+}
+
+/// Opens a tcp connection, using the bind address as the local end if one is configured
+async fn dial(host: &str, port: u16, bind_address: Option<IpAddr>) -> Result<TcpStream> {
+    let mut last_error = None;
+
+    for address in lookup_host((host, port)).await? {
+        let socket = match address {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        if let Some(bind_address) = bind_address {
+            if bind_address.is_ipv4() != address.is_ipv4() {
+                continue;
+            }
+            socket.bind(SocketAddr::new(bind_address, 0))?;
+        }
+
+        match socket.connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(format!("Could not connect to {host}:{port}: {e}").into()),
+        None => Err(format!("No usable address found for {host}:{port}").into()),
+    }
+}
+
+async fn open_tls(
+    config: &Config,
+    stream: TcpStream,
+) -> Result<tokio_native_tls::TlsStream<TcpStream>> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    // A pinned certificate takes the place of the usual chain and hostname checks, which lets
+    // it work with self signed certificates too
+    let skip_validation =
+        config.irc_tls_accept_invalid_certs || config.irc_tls_fingerprint.is_some();
+    builder
+        .danger_accept_invalid_certs(skip_validation)
+        .danger_accept_invalid_hostnames(skip_validation);
+
+    if let Some(path) = &config.irc_client_cert {
+        let archive = std::fs::read(path)
+            .map_err(|e| format!("Could not read irc client certificate {path}: {e}"))?;
+        let password = config
+            .irc_client_cert_password
+            .as_deref()
+            .unwrap_or_default();
+        builder.identity(native_tls::Identity::from_pkcs12(&archive, password)?);
+    }
+
+    let connector = tokio_native_tls::TlsConnector::from(builder.build()?);
+    let stream = connector.connect(&config.irc_host, stream).await?;
+
+    if let Some(expected) = &config.irc_tls_fingerprint {
+        let certificate = stream
+            .get_ref()
+            .peer_certificate()?
+            .ok_or("The irc server did not present a certificate")?;
+        let actual = Fingerprint(Sha256::digest(certificate.to_der()?).into());
+
+        if &actual != expected {
+            return Err(format!(
+                "The irc server certificate {actual} does not match the pinned fingerprint \
+                 {expected}"
+            )
+            .into());
+        }
+    }
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connects to a fresh relay socket, sends `sent` and returns what the relay made of it
+    async fn accept(sent: &str) -> std::result::Result<String, String> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(sent.as_bytes()).await.unwrap();
+        drop(client);
+
+        let (local, _) = listener.accept().await.unwrap();
+        let (mut reader, _) = accept_client(local, "secret").await?;
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        Ok(rest)
+    }
+
+    #[tokio::test]
+    async fn the_token_is_stripped_from_the_client_connection() {
+        assert_eq!(
+            accept("BRIDGE-RELAY-AUTH secret\r\nCAP LS 302\r\n").await,
+            Ok("CAP LS 302\r\n".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn other_local_connections_are_refused() {
+        assert!(accept("CAP LS 302\r\n").await.is_err());
+        assert!(accept("BRIDGE-RELAY-AUTH guess\r\n").await.is_err());
+        assert!(accept("").await.is_err());
+    }
+
+    #[test]
+    fn utf8_lines_are_kept_as_they_are() {
+        let line = "PRIVMSG #bridge :héllo ☃\r\n".as_bytes();
+        let latin1 = parse_encoding("latin1").unwrap();
+        assert!(
+            matches!(decode_line(line, latin1), Cow::Borrowed(decoded) if decoded.as_bytes() == line)
+        );
+    }
+
+    #[test]
+    fn other_lines_use_the_configured_encoding() {
+        let line = b"PRIVMSG #bridge :caf\xe9 \x80 \x93\r\n";
+        assert_eq!(
+            decode_line(line, parse_encoding("latin1").unwrap()),
+            "PRIVMSG #bridge :café € “\r\n"
+        );
+        assert_eq!(
+            decode_line(line, parse_encoding("windows-1252").unwrap()),
+            "PRIVMSG #bridge :café € “\r\n"
+        );
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced() {
+        assert_eq!(
+            decode_line(b"PRIVMSG #bridge :caf\xe9\xff\r\n", encoding_rs::UTF_8),
+            "PRIVMSG #bridge :caf\u{FFFD}\u{FFFD}\r\n"
+        );
+        assert_eq!(
+            decode_line(b"PRIVMSG #bridge :\x82\r\n", encoding_rs::SHIFT_JIS),
+            "PRIVMSG #bridge :\u{FFFD}\r\n"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! How Discord users are shown on irc. The nick goes into a configurable template, can be coloured
+//! by user so people are easy to tell apart, and can have a zero-width space put in it so irc
+//! users with the same nick are not highlighted by every message.
+
+use crate::fnv::fnv1a;
+use crate::Config;
+
+/// Where the nick goes in the nick template
+const NICK_PLACEHOLDER: &str = "{nick}";
+
+const ZERO_WIDTH_SPACE: char = '\u{200B}';
+
+/// mIRC colours that are readable on both light and dark backgrounds
+const NICK_COLOURS: [u8; 10] = [2, 3, 4, 5, 6, 7, 10, 11, 12, 13];
+
+/// Checks that a nick template has somewhere to put the nick
+pub fn parse_nick_template(template: &str) -> std::result::Result<String, String> {
+    if template.contains(NICK_PLACEHOLDER) {
+        Ok(template.to_string())
+    } else {
+        Err(format!("the nick template must contain {NICK_PLACEHOLDER}"))
+    }
+}
+
+/// The same Discord user always gets the same colour, across restarts too
+fn nick_colour(discord_id: u64) -> u8 {
+    NICK_COLOURS[(fnv1a(&discord_id.to_le_bytes()) % NICK_COLOURS.len() as u64) as usize]
+}
+
+/// Formats a Discord user's nick for the start of a line relayed to irc
+pub fn format_nick(config: &Config, nick: &str, discord_id: u64) -> String {
+    NickFormat {
+        template: &config.irc_nick_template,
+        anti_highlight: config.irc_anti_highlight,
+        colours: config.irc_nick_colours,
+    }
+    .format(nick, discord_id)
+}
+
+/// The parts of the config that decide how a nick is shown
+struct NickFormat<'a> {
+    template: &'a str,
+    anti_highlight: bool,
+    colours: bool,
+}
+
+impl NickFormat<'_> {
+    fn format(&self, nick: &str, discord_id: u64) -> String {
+        let mut nick = nick.to_string();
+        if self.anti_highlight
+            && let Some((_, first)) = nick.char_indices().next()
+        {
+            nick.insert(first.len_utf8(), ZERO_WIDTH_SPACE);
+        }
+        if self.colours {
+            // Two digits, so a nick starting with a digit is not read as part of the colour
+            nick = format!("\x03{:02}{nick}\x03", nick_colour(discord_id));
+        }
+        self.template.replace(NICK_PLACEHOLDER, &nick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: NickFormat = NickFormat {
+        template: "<{nick}>",
+        anti_highlight: false,
+        colours: false,
+    };
+
+    #[test]
+    fn templates_need_the_placeholder() {
+        assert_eq!(parse_nick_template("[{nick}]").unwrap(), "[{nick}]");
+        assert!(parse_nick_template("<nick>").is_err());
+    }
+
+    #[test]
+    fn the_nick_goes_where_the_template_says() {
+        assert_eq!(PLAIN.format("alice", 1), "<alice>");
+        let format = NickFormat {
+            template: "[d] {nick}:",
+            ..PLAIN
+        };
+        assert_eq!(format.format("alice", 1), "[d] alice:");
+    }
+
+    #[test]
+    fn the_zero_width_space_goes_after_the_first_character() {
+        let format = NickFormat {
+            anti_highlight: true,
+            ..PLAIN
+        };
+        assert_eq!(format.format("alice", 1), "<a\u{200B}lice>");
+        assert_eq!(format.format("éclair", 1), "<é\u{200B}clair>");
+        assert_eq!(format.format("", 1), "<>");
+    }
+
+    #[test]
+    fn colours_are_fixed_per_user() {
+        // Pinned, so a change to the hash that recolours everyone is noticed
+        assert_eq!(nick_colour(0), 7);
+        assert_eq!(
+            nick_colour(80351110224678912),
+            nick_colour(80351110224678912)
+        );
+        for id in 0..100 {
+            assert!(NICK_COLOURS.contains(&nick_colour(id)));
+        }
+        let format = NickFormat {
+            anti_highlight: true,
+            colours: true,
+            ..PLAIN
+        };
+        assert_eq!(format.format("7of9", 0), "<\x03077\u{200B}of9\x03>");
+    }
+}
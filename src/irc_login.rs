@@ -0,0 +1,378 @@
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use irc::client::{ClientStream, Sender};
+use irc::proto::{CapSubCommand, Command, Message, NegotiationVersion, Response};
+use serenity::futures::StreamExt;
+
+use crate::irc_accounts::AccountTracker;
+use crate::irc_caps::{cap_list, Capabilities, WANTED_CAPS};
+use crate::irc_nick::NickTracker;
+use crate::{Config, Result};
+
+/// How long registration and authentication may take before we give up on the server
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// AUTHENTICATE payloads have to be split into chunks of at most this many bytes
+const SASL_CHUNK_SIZE: usize = 400;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaslMechanism {
+    /// Authenticate with an account name and password
+    Plain,
+    /// Authenticate with the TLS client certificate
+    External,
+}
+
+impl SaslMechanism {
+    fn name(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::External => "EXTERNAL",
+        }
+    }
+}
+
+/// Checks that the authentication options in the config are complete before we connect
+pub fn validate_config(config: &Config) -> Result<()> {
+    match config.irc_sasl_mechanism {
+        Some(SaslMechanism::Plain) if config.irc_sasl_password.is_none() => {
+            Err("SASL PLAIN needs a password, set BRIDGE_IRC_SASL_PASSWORD".into())
+        }
+        Some(SaslMechanism::External) if !config.irc_tls || config.irc_client_cert.is_none() => {
+            Err(
+                "SASL EXTERNAL needs TLS and a client certificate, set BRIDGE_IRC_CLIENT_CERT"
+                    .into(),
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Registers with the irc server, authenticates the bridge nick and joins the bridged channel
+/// once authentication has succeeded.
+pub async fn login(
+    config: &Config,
+    sender: &Sender,
+    stream: &mut ClientStream,
+    nick: &NickTracker,
+    caps: &Capabilities,
+    accounts: &AccountTracker,
+) -> Result<()> {
+    tokio::time::timeout(
+        LOGIN_TIMEOUT,
+        register(config, sender, stream, nick, caps, accounts),
+    )
+    .await
+    .map_err(|_| "Timed out waiting for the irc server to finish logging in")??;
+
+    sender.send_join(&config.irc_channel)?;
+
+    // If the configured nick was taken we are running under an alternate, so start trying to get
+    // it back straight away rather than waiting for the first reclaim interval
+    nick.reclaim()?;
+
+    Ok(())
+}
+
+async fn register(
+    config: &Config,
+    sender: &Sender,
+    stream: &mut ClientStream,
+    nick: &NickTracker,
+    caps: &Capabilities,
+    accounts: &AccountTracker,
+) -> Result<()> {
+    let mechanism = config.irc_sasl_mechanism;
+
+    // Capability negotiation holds registration open until we send CAP END, which gives us the
+    // chance to authenticate before the server considers us connected
+    sender.send_cap_ls(NegotiationVersion::V302)?;
+    if let Some(password) = &config.irc_password {
+        sender.send(Command::PASS(password.clone()))?;
+    }
+    nick.register()?;
+    sender.send(Command::USER(
+        config.irc_username().to_string(),
+        "0".to_string(),
+        config.irc_realname().to_string(),
+    ))?;
+
+    let mut sasl_done = mechanism.is_none();
+    let mut waiting_for_nickserv = false;
+    let mut offered: Vec<String> = vec![];
+
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            Err(irc::error::Error::NoUsableNick) => {
+                nick.nick_refused()?;
+                continue;
+            }
+            message => message?,
+        };
+        nick.handle_message(&message)?;
+        caps.handle_message(&message)?;
+        accounts.handle_message(&message, false)?;
+
+        match &message.command {
+            Command::CAP(_, CapSubCommand::LS, first, second) => {
+                offered.extend(cap_list(first, second).map(String::from));
+
+                // A "*" before the list means the server has more lines of capabilities to send
+                if second.is_some() && first.as_deref() == Some("*") {
+                    continue;
+                }
+
+                let mut wanted: Vec<&str> = WANTED_CAPS
+                    .iter()
+                    .copied()
+                    .filter(|cap| offered.iter().any(|offer| offer == cap))
+                    .collect();
+                if mechanism.is_some() {
+                    if !offered.iter().any(|offer| offer == "sasl") {
+                        return Err("The irc server does not support SASL authentication".into());
+                    }
+                    wanted.push("sasl");
+                }
+
+                if wanted.is_empty() {
+                    sender.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+                } else {
+                    sender.send(Command::CAP(
+                        None,
+                        CapSubCommand::REQ,
+                        None,
+                        Some(wanted.join(" ")),
+                    ))?;
+                }
+            }
+            Command::CAP(_, CapSubCommand::ACK, _, _) => match mechanism {
+                Some(mechanism) if caps.has("sasl") => {
+                    sender.send(Command::AUTHENTICATE(mechanism.name().to_string()))?;
+                }
+                _ => sender.send(Command::CAP(None, CapSubCommand::END, None, None))?,
+            },
+            Command::CAP(_, CapSubCommand::NAK, _, _) => {
+                if mechanism.is_some() {
+                    return Err("The irc server refused the SASL capability".into());
+                }
+                println!("LOG: irc server refused our capability request");
+                sender.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+            }
+            Command::AUTHENTICATE(data) if data == "+" => {
+                for chunk in sasl_response(config) {
+                    sender.send(Command::AUTHENTICATE(chunk))?;
+                }
+            }
+            Command::Response(Response::RPL_SASLSUCCESS, _) => {
+                println!("LOG: SASL authentication succeeded");
+                sasl_done = true;
+                sender.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+            }
+            Command::Response(
+                Response::ERR_SASLFAIL
+                | Response::ERR_SASLTOOLONG
+                | Response::ERR_SASLABORT
+                | Response::ERR_NICKLOCKED,
+                args,
+            ) => {
+                return Err(format!(
+                    "SASL authentication failed: {}",
+                    args.last().map(String::as_str).unwrap_or("no reason given")
+                )
+                .into());
+            }
+            Command::Response(Response::ERR_PASSWDMISMATCH, _) => {
+                return Err("The irc server rejected the server password".into());
+            }
+            Command::Response(Response::RPL_ENDOFMOTD | Response::ERR_NOMOTD, _) => {
+                if !sasl_done {
+                    return Err("The irc server finished registration without SASL".into());
+                }
+
+                match &config.irc_nickserv_password {
+                    Some(password) if mechanism.is_none() => {
+                        // Name the account explicitly, we may be on an alternate nick
+                        sender.send(Command::NICKSERV(vec![
+                            "IDENTIFY".to_string(),
+                            config.irc_nick.clone(),
+                            password.clone(),
+                        ]))?;
+                        waiting_for_nickserv = true;
+                    }
+                    _ => return Ok(()),
+                }
+            }
+            Command::Response(Response::RPL_LOGGEDIN, _) if waiting_for_nickserv => {
+                println!("LOG: Identified to NickServ");
+                return Ok(());
+            }
+            Command::NOTICE(_, text) if waiting_for_nickserv && is_from_nickserv(&message) => {
+                match nickserv_reply(text) {
+                    Some(true) => {
+                        println!("LOG: Identified to NickServ");
+                        return Ok(());
+                    }
+                    Some(false) => {
+                        return Err(format!("NickServ identification failed: {text}").into());
+                    }
+                    None => {}
+                }
+            }
+            Command::ERROR(reason) => {
+                return Err(format!("The irc server closed the connection: {reason}").into());
+            }
+            _ => {}
+        }
+    }
+
+    Err("The irc connection closed before logging in".into())
+}
+
+/// Builds the AUTHENTICATE messages that answer the server's `AUTHENTICATE +` challenge
+fn sasl_response(config: &Config) -> Vec<String> {
+    let account = config
+        .irc_sasl_username
+        .as_deref()
+        .unwrap_or(&config.irc_nick);
+    let password = config.irc_sasl_password.as_deref().unwrap_or_default();
+    sasl_chunks(&sasl_payload(config.irc_sasl_mechanism, account, password))
+}
+
+fn sasl_payload(mechanism: Option<SaslMechanism>, account: &str, password: &str) -> String {
+    match mechanism {
+        Some(SaslMechanism::Plain) => STANDARD.encode(format!("{account}\0{account}\0{password}")),
+        // EXTERNAL takes its identity from the client certificate, so the payload is empty
+        _ => String::new(),
+    }
+}
+
+/// Splits a base64 payload into AUTHENTICATE arguments
+fn sasl_chunks(payload: &str) -> Vec<String> {
+    let mut chunks: Vec<String> = payload
+        .as_bytes()
+        .chunks(SASL_CHUNK_SIZE)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect();
+
+    // An empty payload, or one that ends exactly on a chunk boundary, is terminated with "+"
+    if payload.len() % SASL_CHUNK_SIZE == 0 {
+        chunks.push("+".to_string());
+    }
+
+    chunks
+}
+
+fn is_from_nickserv(message: &Message) -> bool {
+    message
+        .source_nickname()
+        .is_some_and(|nick| nick.eq_ignore_ascii_case("NickServ"))
+}
+
+/// Interprets a NickServ notice sent after IDENTIFY, returning `None` for unrelated notices
+fn nickserv_reply(text: &str) -> Option<bool> {
+    let text = text.to_lowercase();
+
+    if text.contains("you are now identified")
+        || text.contains("password accepted")
+        || text.contains("you are now logged in")
+    {
+        Some(true)
+    } else if text.contains("invalid password")
+        || text.contains("password incorrect")
+        || text.contains("isn't registered")
+        || text.contains("is not a registered")
+        || text.contains("not registered")
+    {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn chunks_rebuild_the_payload(data in prop::collection::vec(any::<u8>(), 0..1000)) {
+            let payload = STANDARD.encode(data);
+            let chunks = sasl_chunks(&payload);
+            prop_assert!(chunks.iter().all(|chunk| chunk.len() <= SASL_CHUNK_SIZE));
+            // The server knows the payload is over when a chunk is shorter than the limit
+            prop_assert!(chunks.last().unwrap().len() < SASL_CHUNK_SIZE);
+            // Base64 comes in fours, so a lone + can only be the terminator
+            let data = match chunks.split_last() {
+                Some((last, data)) if last == "+" => data,
+                _ => &chunks[..],
+            };
+            prop_assert_eq!(data.concat(), payload);
+        }
+    }
+
+    #[test]
+    fn plain_sends_the_account_twice() {
+        assert_eq!(
+            sasl_payload(Some(SaslMechanism::Plain), "bridge", "hunter2"),
+            "YnJpZGdlAGJyaWRnZQBodW50ZXIy"
+        );
+        assert_eq!(
+            sasl_chunks("YnJpZGdlAGJyaWRnZQBodW50ZXIy"),
+            ["YnJpZGdlAGJyaWRnZQBodW50ZXIy"]
+        );
+    }
+
+    #[test]
+    fn external_sends_an_empty_payload() {
+        let payload = sasl_payload(Some(SaslMechanism::External), "bridge", "");
+        assert_eq!(payload, "");
+        assert_eq!(sasl_chunks(&payload), ["+"]);
+    }
+
+    #[test]
+    fn payloads_on_a_chunk_boundary_end_with_a_plus() {
+        let exact = "a".repeat(SASL_CHUNK_SIZE);
+        assert_eq!(sasl_chunks(&exact), [exact.as_str(), "+"]);
+
+        let over = "a".repeat(SASL_CHUNK_SIZE + 1);
+        assert_eq!(sasl_chunks(&over), [exact.as_str(), "a"]);
+
+        let twice = "a".repeat(2 * SASL_CHUNK_SIZE);
+        assert_eq!(sasl_chunks(&twice), [exact.as_str(), exact.as_str(), "+"]);
+    }
+
+    #[test]
+    fn nickserv_replies_are_recognised() {
+        for text in [
+            "You are now identified for \x02bridge\x02.",
+            "Password accepted - you are now recognized.",
+        ] {
+            assert_eq!(nickserv_reply(text), Some(true), "{text}");
+        }
+        for text in [
+            "Invalid password for \x02bridge\x02.",
+            "Password incorrect.",
+            "\x02bridge\x02 is not a registered nickname.",
+            "Nick bridge isn't registered.",
+        ] {
+            assert_eq!(nickserv_reply(text), Some(false), "{text}");
+        }
+        assert_eq!(nickserv_reply("This nickname is registered."), None);
+    }
+
+    #[test]
+    fn only_nickserv_is_nickserv() {
+        let notice = |source: &str| -> Message {
+            format!(":{source} NOTICE bridge :Password accepted")
+                .parse()
+                .unwrap()
+        };
+        assert!(is_from_nickserv(&notice("NickServ!NickServ@services.")));
+        assert!(is_from_nickserv(&notice("nickserv!NickServ@services.")));
+        assert!(!is_from_nickserv(&notice("mallory!m@host")));
+        assert!(!is_from_nickserv(&notice("services.example.net")));
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use irc::proto::{Command, Message, Response};
+use tokio::sync::oneshot;
+
+use crate::irc_nick::fold_nick;
+
+/// Requests for a channel that are still waiting for an answer, oldest first
+type Waiting = VecDeque<oneshot::Sender<Vec<String>>>;
+
+/// Collects NAMES replies, which may be split over many RPL_NAMREPLY lines, and hands the full
+/// list to whoever asked once RPL_ENDOFNAMES arrives. Replies come back in the order the requests
+/// were sent, so waiting requests for a channel are answered oldest first. The server also sends
+/// a list on its own right after our JOIN, which is left out so it does not take the place of the
+/// reply a request is waiting for.
+#[derive(Clone, Debug, Default)]
+pub struct NamesCollector {
+    waiting: Arc<Mutex<HashMap<String, Waiting>>>,
+    partial: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// Lists coming for channels we just joined, that nobody asked for
+    unsolicited: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl NamesCollector {
+    /// Registers a request for the channel's names, to be called just before sending NAMES
+    pub fn expect(&self, channel: &str, reply: oneshot::Sender<Vec<String>>) {
+        self.waiting
+            .lock()
+            .expect("names lock poisoned")
+            .entry(fold_nick(channel))
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Handles NAMES replies, returning true if the message was one. Our own JOINs are noted but
+    /// not taken, others need to see them too.
+    pub fn handle_message(&self, message: &Message, from_us: bool) -> bool {
+        match &message.command {
+            Command::JOIN(channels, _, _) if from_us => {
+                let mut unsolicited = self.unsolicited.lock().expect("names lock poisoned");
+                for channel in channels.split(',') {
+                    *unsolicited.entry(fold_nick(channel)).or_default() += 1;
+                }
+                false
+            }
+            // <us> <symbol> <channel> :<names>
+            Command::Response(Response::RPL_NAMREPLY, args) => {
+                if let [_, _, channel, names] = args.as_slice() {
+                    self.partial
+                        .lock()
+                        .expect("names lock poisoned")
+                        .entry(fold_nick(channel))
+                        .or_default()
+                        .extend(names.split_whitespace().map(String::from));
+                }
+                true
+            }
+            // <us> <channel> :End of /NAMES list
+            Command::Response(Response::RPL_ENDOFNAMES, args) => {
+                let Some(channel) = args.get(1).map(|channel| fold_nick(channel)) else {
+                    return true;
+                };
+                let names = self
+                    .partial
+                    .lock()
+                    .expect("names lock poisoned")
+                    .remove(&channel)
+                    .unwrap_or_default();
+
+                let mut unsolicited = self.unsolicited.lock().expect("names lock poisoned");
+                if let Some(count) = unsolicited.get_mut(&channel) {
+                    *count -= 1;
+                    if *count == 0 {
+                        unsolicited.remove(&channel);
+                    }
+                    return true;
+                }
+
+                let mut waiting = self.waiting.lock().expect("names lock poisoned");
+                // Skip requests whose caller already gave up waiting
+                while let Some(reply) = waiting.get_mut(&channel).and_then(VecDeque::pop_front) {
+                    if reply.send(names.clone()).is_ok() {
+                        break;
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(names: &NamesCollector, line: &str, from_us: bool) {
+        names.handle_message(&line.parse().unwrap(), from_us);
+    }
+
+    #[test]
+    fn the_list_sent_on_join_answers_nobody() {
+        let names = NamesCollector::default();
+        let (reply, mut answer) = oneshot::channel();
+        names.expect("#bridge", reply);
+
+        handle(&names, ":bridge!b@host JOIN #Bridge", true);
+        handle(&names, ":server 353 bridge = #bridge :bridge alice", false);
+        handle(
+            &names,
+            ":server 366 bridge #bridge :End of /NAMES list",
+            false,
+        );
+        assert!(answer.try_recv().is_err());
+
+        handle(
+            &names,
+            ":server 353 bridge = #bridge :bridge alice bob",
+            false,
+        );
+        handle(
+            &names,
+            ":server 366 bridge #bridge :End of /NAMES list",
+            false,
+        );
+        assert_eq!(answer.try_recv().unwrap(), ["bridge", "alice", "bob"]);
+    }
+
+    #[test]
+    fn other_joins_change_nothing() {
+        let names = NamesCollector::default();
+        let (reply, mut answer) = oneshot::channel();
+        names.expect("#bridge", reply);
+
+        handle(&names, ":carol!c@host JOIN #bridge", false);
+        handle(&names, ":server 353 bridge = #bridge :bridge carol", false);
+        handle(
+            &names,
+            ":server 366 bridge #bridge :End of /NAMES list",
+            false,
+        );
+        assert_eq!(answer.try_recv().unwrap(), ["bridge", "carol"]);
+    }
+}
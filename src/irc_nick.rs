@@ -0,0 +1,230 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use irc::client::Sender;
+use irc::proto::{Command, Message, Response};
+
+use crate::{Config, Result};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GhostCommand {
+    /// Disconnect whoever holds the nick
+    Ghost,
+    /// Have services take the nick back (Anope)
+    Recover,
+    /// Have services take the nick back (Atheme)
+    Regain,
+}
+
+impl GhostCommand {
+    fn name(&self) -> &'static str {
+        match self {
+            GhostCommand::Ghost => "GHOST",
+            GhostCommand::Recover => "RECOVER",
+            GhostCommand::Regain => "REGAIN",
+        }
+    }
+}
+
+/// Lowercases a nick using the rfc1459 casemapping most irc servers use
+pub fn fold_nick(nick: &str) -> String {
+    nick.chars()
+        .map(|c| match c {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// Compares two nicks using the rfc1459 casemapping
+pub fn nick_eq(a: &str, b: &str) -> bool {
+    fold_nick(a) == fold_nick(b)
+}
+
+/// Alternate nicks to fall back to when the configured nick is taken
+pub fn alternate_nicks(config: &Config) -> Vec<String> {
+    if config.irc_alt_nicks.is_empty() {
+        vec![
+            format!("{}_", config.irc_nick),
+            format!("{}__", config.irc_nick),
+        ]
+    } else {
+        config.irc_alt_nicks.clone()
+    }
+}
+
+/// Keeps track of the nick the bridge is actually using and tries to get the configured one back
+/// whenever we had to settle for an alternate.
+///
+/// The irc client is given no alternate nicks, so it never sends a NICK of its own. Instead it
+/// reports every refused nick as `NoUsableNick`, and the tracker picks the next alternate while
+/// registering, or stays on the nick it has when a reclaim fails.
+#[derive(Clone, Debug)]
+pub struct NickTracker {
+    current: Arc<RwLock<String>>,
+    /// The nick we last asked for that the server has not answered yet
+    pending: Arc<RwLock<Option<String>>>,
+    registered: Arc<AtomicBool>,
+    primary: String,
+    alternates: Vec<String>,
+    ghost_command: Option<GhostCommand>,
+    nickserv_password: Option<String>,
+    sender: Sender,
+}
+
+impl NickTracker {
+    pub fn new(config: &Config, sender: Sender) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(config.irc_nick.clone())),
+            pending: Default::default(),
+            registered: Default::default(),
+            primary: config.irc_nick.clone(),
+            alternates: alternate_nicks(config),
+            ghost_command: config.irc_ghost_command,
+            nickserv_password: config.irc_nickserv_password.clone(),
+            sender,
+        }
+    }
+
+    pub fn current(&self) -> String {
+        self.current.read().expect("nick lock poisoned").clone()
+    }
+
+    pub fn is_current(&self, nick: &str) -> bool {
+        nick_eq(&self.current(), nick)
+    }
+
+    fn has_primary(&self) -> bool {
+        self.is_current(&self.primary)
+    }
+
+    fn set_current(&self, nick: &str) {
+        println!("LOG: Now using irc nick {nick}");
+        *self.current.write().expect("nick lock poisoned") = nick.to_string();
+        *self.pending.write().expect("nick lock poisoned") = None;
+    }
+
+    fn request(&self, nick: &str) -> Result<()> {
+        *self.pending.write().expect("nick lock poisoned") = Some(nick.to_string());
+        self.sender.send(Command::NICK(nick.to_string()))?;
+        Ok(())
+    }
+
+    /// Asks for the configured nick when registering
+    pub fn register(&self) -> Result<()> {
+        self.request(&self.primary)
+    }
+
+    /// Handles the server refusing the nick we asked for. While registering the next alternate is
+    /// tried, and it is an error if there are none left. Afterwards only reclaims ask for a nick,
+    /// so we keep the one we have.
+    pub fn nick_refused(&self) -> Result<()> {
+        let Some(refused) = self.pending.write().expect("nick lock poisoned").take() else {
+            return Ok(());
+        };
+
+        if self.registered.load(Ordering::Relaxed) {
+            println!(
+                "LOG: Could not reclaim irc nick {refused}, staying on {}",
+                self.current()
+            );
+            return Ok(());
+        }
+
+        let next = std::iter::once(&self.primary)
+            .chain(&self.alternates)
+            .skip_while(|nick| !nick_eq(nick, &refused))
+            .nth(1);
+        match next {
+            Some(next) => {
+                println!("LOG: irc nick {refused} is not available, trying {next}");
+                self.request(next)
+            }
+            None => Err(format!(
+                "None of the irc nicks are available, the last tried was {refused}"
+            )
+            .into()),
+        }
+    }
+
+    /// Updates the current nick from server messages, and tries to reclaim the configured nick as
+    /// soon as it looks like it has been freed up.
+    pub fn handle_message(&self, message: &Message) -> Result<()> {
+        match &message.command {
+            Command::Response(Response::RPL_WELCOME, args) => {
+                if let Some(nick) = args.first() {
+                    self.set_current(nick);
+                }
+                self.registered.store(true, Ordering::Relaxed);
+            }
+            Command::NICK(new_nick) => match message.source_nickname() {
+                Some(old_nick) if self.is_current(old_nick) => self.set_current(new_nick),
+                Some(old_nick) if nick_eq(old_nick, &self.primary) && !self.has_primary() => {
+                    self.send_nick()?
+                }
+                _ => {}
+            },
+            Command::QUIT(_) => {
+                if let Some(nick) = message.source_nickname()
+                    && nick_eq(nick, &self.primary)
+                    && !self.has_primary()
+                {
+                    self.send_nick()?
+                }
+            }
+            Command::Response(Response::RPL_ISON, args) => {
+                let online = args.last().map(String::as_str).unwrap_or_default();
+                if !self.has_primary()
+                    && !online
+                        .split_whitespace()
+                        .any(|nick| nick_eq(nick, &self.primary))
+                {
+                    self.send_nick()?
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Asks services to free the configured nick and checks whether it is available again. The
+    /// NICK itself is only sent once the ISON reply shows nobody is using it.
+    pub fn reclaim(&self) -> Result<()> {
+        if self.has_primary() {
+            return Ok(());
+        }
+
+        if let Some(ghost_command) = self.ghost_command {
+            let mut args = vec![ghost_command.name().to_string(), self.primary.clone()];
+            if let Some(password) = &self.nickserv_password {
+                args.push(password.clone());
+            }
+            self.sender.send(Command::NICKSERV(args))?;
+        }
+
+        self.sender
+            .send(Command::ISON(vec![self.primary.clone()]))?;
+        Ok(())
+    }
+
+    fn send_nick(&self) -> Result<()> {
+        println!("LOG: Trying to reclaim irc nick {}", self.primary);
+        self.request(&self.primary)
+    }
+}
+
+/// Periodically tries to take the configured nick back while running under an alternate
+pub async fn reclaim_nick(config: Config, nick: NickTracker) -> Result<()> {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.irc_nick_reclaim_interval.max(1)));
+
+    loop {
+        interval.tick().await;
+        nick.reclaim()?;
+    }
+}
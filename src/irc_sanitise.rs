@@ -0,0 +1,226 @@
+//! The last stage before anything is sent to irc. A line break would end the PRIVMSG early and let
+//! the rest be read as another command, and a \x01 would let Discord users send CTCP requests, so
+//! text is split into separate lines and every control character irc gives a meaning is dropped.
+//! The formatting codes irc clients use for colours and styles are kept.
+
+/// Bold, colour, hex colour, reset, monospace, reverse, italic, strikethrough and underline
+const FORMATTING: [char; 9] = [
+    '\x02', '\x03', '\x04', '\x0f', '\x11', '\x16', '\x1d', '\x1e', '\x1f',
+];
+
+fn sanitise_char(c: char) -> Option<char> {
+    match c {
+        '\t' => Some(' '),
+        c if FORMATTING.contains(&c) => Some(c),
+        // Includes NUL, the CTCP delimiter and the C1 controls
+        c if c.is_control() => None,
+        c => Some(c),
+    }
+}
+
+/// Splits text into the lines to send, leaving out lines with nothing left to show
+pub fn sanitise_lines(text: &str) -> Vec<String> {
+    text.split(['\r', '\n'])
+        .map(|line| line.chars().filter_map(sanitise_char).collect::<String>())
+        .filter(|line| !line.trim().is_empty())
+        .collect()
+}
+
+/// The most bytes an irc line can have, with the \r\n at the end
+const MAX_LINE_BYTES: usize = 512;
+
+/// Room for the `:nick!user@host ` the server puts in front of a message when it passes it on
+const SOURCE_ALLOWANCE: usize = 100;
+
+/// How much text fits in one PRIVMSG to `target` once the server has added our source
+pub fn max_text_bytes(target: &str) -> usize {
+    MAX_LINE_BYTES
+        .saturating_sub(SOURCE_ALLOWANCE + "PRIVMSG  :\r\n".len() + target.len())
+        .max(1)
+}
+
+/// Cuts a line into pieces of at most `max_bytes`, at the last space that fits where there is one
+pub fn split_to_fit(line: &str, max_bytes: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut rest = line;
+    while rest.len() > max_bytes {
+        let mut end = max_bytes;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A character wider than the limit still has to go somewhere
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let cut = match rest[..end].rfind(' ') {
+            Some(space) if !rest[..space].trim().is_empty() => space,
+            _ => end,
+        };
+        pieces.push(rest[..cut].to_string());
+        rest = rest[cut..].trim_start_matches(' ');
+    }
+    if !rest.trim().is_empty() {
+        pieces.push(rest.to_string());
+    }
+    pieces
+}
+
+/// Splits text into the lines to send to `target`, each cut to fit in one PRIVMSG
+pub fn message_lines(target: &str, text: &str) -> Vec<String> {
+    let max_bytes = max_text_bytes(target);
+    sanitise_lines(text)
+        .iter()
+        .flat_map(|line| split_to_fit(line, max_bytes))
+        .collect()
+}
+
+/// Splits text into lines like [`message_lines`], putting `prefix` and a space in front of every
+/// one, so no line reaches irc without saying who it is from
+pub fn prefix_lines(target: &str, prefix: &str, text: &str) -> Vec<String> {
+    let max_bytes = max_text_bytes(target)
+        .saturating_sub(prefix.len() + 1)
+        .max(1);
+    sanitise_lines(text)
+        .iter()
+        .flat_map(|line| split_to_fit(line, max_bytes))
+        .map(|line| format!("{prefix} {line}"))
+        .collect()
+}
+
+/// Whether a message target is a single nick or channel. A space or comma would send the message
+/// somewhere else, or to several places at once.
+pub fn is_valid_target(target: &str) -> bool {
+    !target.is_empty()
+        && !target
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == ',')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Text that is mostly printable, with the bytes that matter to irc mixed in
+    fn irc_text() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                4 => any::<char>(),
+                1 => prop::sample::select(vec!['\r', '\n', '\0', '\x01', '\x03', '\t']),
+            ],
+            0..64,
+        )
+        .prop_map(|chars| chars.into_iter().collect())
+    }
+
+    proptest! {
+        #[test]
+        fn lines_never_contain_protocol_bytes(text in irc_text()) {
+            for line in sanitise_lines(&text) {
+                prop_assert!(!line.contains(['\r', '\n', '\0', '\x01']), "{line:?}");
+            }
+        }
+
+        #[test]
+        fn lines_are_never_blank(text in irc_text()) {
+            for line in sanitise_lines(&text) {
+                prop_assert!(!line.trim().is_empty());
+            }
+        }
+
+        #[test]
+        fn sanitising_twice_changes_nothing(text in irc_text()) {
+            for line in sanitise_lines(&text) {
+                prop_assert_eq!(sanitise_lines(&line), vec![line.clone()]);
+            }
+        }
+
+        #[test]
+        fn there_are_never_more_lines_than_sent(text in irc_text()) {
+            prop_assert!(sanitise_lines(&text).len() <= text.split(['\r', '\n']).count());
+        }
+
+        #[test]
+        fn ordinary_text_is_kept(text in "[^\\p{Cc}]*[^\\p{Cc}\\s][^\\p{Cc}]*") {
+            prop_assert_eq!(sanitise_lines(&text), vec![text.clone()]);
+        }
+
+        #[test]
+        fn forged_ctcp_is_defused(command in "[A-Z]{1,10}", args in "[a-z ]{0,20}") {
+            let forged = format!("\x01{command} {args}\x01");
+            for line in sanitise_lines(&forged) {
+                prop_assert!(!line.starts_with('\x01'));
+            }
+        }
+
+        #[test]
+        fn every_line_has_the_prefix(text in irc_text()) {
+            for line in prefix_lines("#bridge", "<alice>", &text) {
+                prop_assert!(line.starts_with("<alice> "), "{line:?}");
+            }
+        }
+
+        #[test]
+        fn pieces_fit_and_keep_the_text(text in "[a-zé ]{0,300}", max_bytes in 2usize..40) {
+            let pieces = split_to_fit(&text, max_bytes);
+            for piece in &pieces {
+                prop_assert!(piece.len() <= max_bytes, "{piece:?}");
+            }
+            prop_assert_eq!(pieces.concat().replace(' ', ""), text.replace(' ', ""));
+        }
+
+        #[test]
+        fn lines_fit_in_a_privmsg(text in irc_text(), target in "#[a-z]{1,20}") {
+            for line in message_lines(&target, &text) {
+                prop_assert!(line.len() <= max_text_bytes(&target));
+            }
+        }
+
+        #[test]
+        fn targets_with_separators_are_refused(
+            nick in "[a-zA-Z]{1,9}",
+            separator in prop::sample::select(vec![" ", ",", "\r\n", "\0", "\t"]),
+            rest in "[a-zA-Z#]{0,9}",
+        ) {
+            prop_assert!(is_valid_target(&nick));
+            let target = format!("{nick}{separator}{rest}");
+            prop_assert!(!is_valid_target(&target));
+        }
+    }
+
+    #[test]
+    fn formatting_codes_are_kept() {
+        assert_eq!(
+            sanitise_lines("\x0305alice\x03 said \x02hi\x02"),
+            vec!["\x0305alice\x03 said \x02hi\x02"]
+        );
+    }
+
+    #[test]
+    fn line_breaks_split_the_message() {
+        assert_eq!(
+            sanitise_lines("hi\r\nQUIT :bye\n\n"),
+            vec!["hi", "QUIT :bye"]
+        );
+    }
+
+    #[test]
+    fn carriage_returns_cannot_start_an_unprefixed_line() {
+        assert_eq!(
+            prefix_lines("#bridge", "<alice>", "hi\r<admin> do X"),
+            vec!["<alice> hi", "<alice> <admin> do X"]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split_at_spaces() {
+        let word = "a".repeat(100);
+        let line = [word.as_str(); 6].join(" ");
+        let lines = prefix_lines("#bridge", "<alice>", &line);
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            assert!(line.starts_with("<alice> a"));
+            assert!(line.len() <= max_text_bytes("#bridge"));
+        }
+    }
+}
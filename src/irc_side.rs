@@ -1,27 +1,99 @@
 use clap::{Parser, Subcommand};
 use irc::client::ClientStream;
-use irc::proto::Response;
+use irc::proto::{Message, Prefix};
 use serenity::futures::StreamExt;
 use serenity::http::client::*;
-use serenity::model::prelude::application_command::ApplicationCommandInteraction;
-use serenity::model::prelude::interaction::InteractionResponseType;
-use serenity::model::prelude::{GuildId, Member};
-use sqlx::SqlitePool;
-use tokio::sync::mpsc::Receiver;
+use serenity::model::prelude::{GuildId, Member, UserId};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::direction::{Direction, PairDirection};
+use crate::discord::escape_markdown;
+use crate::echo::{self, EchoFilter};
+use crate::filters::{self, Filtered, Filters};
+use crate::ignores::{IgnoreCommand, IgnoreList};
+use crate::irc_accounts::{AccountChange, AccountTracker};
+use crate::irc_caps::{self, Capabilities, DeliveryTracker};
+use crate::irc_names::NamesCollector;
+use crate::irc_nick::NickTracker;
+use crate::irc_whois::WhoisCollector;
+use crate::linking::{self, PendingLinks, RedeemError};
+use crate::mentions::{self, MentionPolicy, PairMentions};
+use crate::optout::{OptOutStatus, OptOuts};
+use crate::permissions;
+use crate::user_store::{User, UserStore};
+use crate::webhook_name::webhook_username;
 use crate::{BridgeSenders, DiscordRequest};
 
 #[derive(Parser, Clone, Debug)]
 enum IrcBotCommand {
     Avatar {
+        /// Change someone else's avatar, admins only
+        #[arg(long = "for")]
+        target: Option<String>,
         #[command(subcommand)]
         command: AvatarCommand,
     },
     Connect {
-        discord_id: String,
+        code: String,
+    },
+    Unlink,
+    /// Stop your messages from being bridged to Discord
+    Optout,
+    /// Have your messages bridged to Discord again
+    Optin,
+    Whois {
+        target: String,
+    },
+    Links,
+    /// Manage ignored users, admins only
+    Ignore {
+        #[command(subcommand)]
+        command: IgnoreCommand,
+    },
+    /// Reload the ignores and the filter rules, admins only
+    Reload,
+    /// Pause bridging, or only bridge one way, admins only
+    Pause {
+        #[arg(value_enum, default_value_t = Direction::Paused)]
+        direction: Direction,
+    },
+    /// Bridge both ways again, admins only
+    Resume,
+    /// Choose whether messages from irc can ping everyone and roles, admins only
+    Mentions {
+        #[arg(value_enum)]
+        policy: MentionPolicy,
+    },
+    /// Link a nick to a discord user without a code, admins only
+    Link {
+        nick: String,
+        discord_id: u64,
+        /// Services account to verify the link for
+        account: Option<String>,
     },
 }
 
+impl IrcBotCommand {
+    fn requires_admin(&self) -> bool {
+        match self {
+            IrcBotCommand::Avatar { target, .. } => target.is_some(),
+            IrcBotCommand::Links
+            | IrcBotCommand::Ignore { .. }
+            | IrcBotCommand::Reload
+            | IrcBotCommand::Pause { .. }
+            | IrcBotCommand::Resume
+            | IrcBotCommand::Mentions { .. }
+            | IrcBotCommand::Link { .. } => true,
+            IrcBotCommand::Connect { .. }
+            | IrcBotCommand::Unlink
+            | IrcBotCommand::Optout
+            | IrcBotCommand::Optin
+            | IrcBotCommand::Whois { .. } => false,
+        }
+    }
+}
+
 #[derive(Subcommand, Clone, Debug)]
 enum AvatarCommand {
     Url { url: String },
@@ -45,18 +117,21 @@ pub async fn get_avatar_from_guild_member(member: Member) -> String {
 }
 
 pub async fn select_avatar_for_user(
-    pool: &SqlitePool,
+    users: &dyn UserStore,
     http: &Http,
     guild: GuildId,
     nick: String,
+    account: Option<&str>,
 ) -> Option<String> {
-    if let Some(entry) = lookup_nick_in_database(pool, &nick).await {
+    if let Ok(Some(entry)) = users.find_irc_user(&nick, account).await {
         // If a custom avatar is set, use that
         if let Some(avatar) = entry.avatar {
             return Some(avatar);
         // If the User has a discord user associated with their account and is verified, use that
         // user's avatar
-        } else if let Some(discord_nick) = entry.discord_nick && entry.verified {
+        } else if let Some(discord_nick) = entry.discord_nick
+            && entry.verified
+        {
             if let Some(user) = lookup_nick_on_discord(http, guild, discord_nick).await {
                 return Some(get_avatar_from_guild_member(user).await);
             }
@@ -89,52 +164,47 @@ async fn lookup_nick_on_discord(
         })
 }
 
-struct UserInfo {
-    verified: bool,
-    irc_nick: String,
-    discord_id: Option<i64>,
-    discord_nick: Option<String>,
-    discord_name: Option<String>,
-    avatar: Option<String>,
-}
-
-async fn lookup_nick_in_database(pool: &SqlitePool, nick: &String) -> Option<UserInfo> {
-    let mut conn = pool
-        .acquire()
-        .await
-        .expect("Could not make connection to database");
-
-    sqlx::query!("SELECT * FROM users WHERE ircnick = ?", nick)
-        .fetch_one(&mut *conn)
-        .await
-        .ok()
-        .map(|info| UserInfo {
-            verified: info.verified.unwrap_or(false),
-            irc_nick: info.ircnick,
-            discord_id: info.discordid,
-            discord_nick: info.discordnick,
-            discord_name: info.discordname,
-            avatar: info.avatar,
-        })
-}
-
-#[derive(Debug)]
-pub enum IrcResponse {
-    NamesResponse(Vec<String>),
+/// The handles the irc side shares with the rest of the bridge
+#[derive(Clone)]
+pub struct IrcContext {
+    pub users: Arc<dyn UserStore>,
+    pub config: crate::Config,
+    pub senders: BridgeSenders,
+    pub names: NamesCollector,
+    pub whois: WhoisCollector,
+    pub nick_tracker: NickTracker,
+    pub caps: Capabilities,
+    pub delivery: DeliveryTracker,
+    pub accounts: AccountTracker,
+    pub pending_links: PendingLinks,
+    pub ignores: IgnoreList,
+    pub direction: PairDirection,
+    pub opt_outs: OptOuts,
+    pub echoes: EchoFilter,
+    pub mentions: PairMentions,
+    pub filters: Filters,
 }
 
-#[derive(Debug)]
-pub struct IrcResponseCallback {
-    pub interaction: ApplicationCommandInteraction,
+/// Who sent a command to the bot
+struct Caller {
+    nick: String,
+    hostmask: Option<String>,
+    account: Option<String>,
 }
 
 pub async fn irc_receiver(
     mut stream: ClientStream,
-    database_pool: SqlitePool,
-    config: crate::Config,
-    senders: BridgeSenders,
-    mut response_callbacks: Receiver<IrcResponseCallback>,
+    ctx: IrcContext,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let IrcContext {
+        config,
+        names,
+        whois,
+        nick_tracker,
+        caps,
+        delivery,
+        ..
+    } = &ctx;
     let http = Http::new(&config.discord_token);
 
     let webhook = http.get_webhook_from_url(&config.discord_webhook).await?;
@@ -143,120 +213,285 @@ pub async fn irc_receiver(
         .guild_id
         .ok_or("No associated discord guild for webhook")?;
 
-    while let Some(message) = stream.next().await.transpose()? {
-        let actual_message = message.clone();
-
-        match message.command {
-            irc::proto::Command::Response(Response::RPL_NAMREPLY, data) => {
-                println!("Received names reply with content {:?}", data);
-                if let Some(interaction) = response_callbacks.try_recv().ok() {
-                    interaction
-                        .interaction
-                        .create_interaction_response(&http, |w| {
-                            w.kind(InteractionResponseType::Modal)
-                                .interaction_response_data(|w| w.content(data.join("\n")))
-                        })
-                        .await;
-                } else {
-                    println!("No commands waiting");
-                }
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            // The irc client reports every refused NICK this way, the connection is still usable
+            Err(irc::error::Error::NoUsableNick) => {
+                nick_tracker.nick_refused()?;
+                continue;
             }
-            irc::proto::Command::PRIVMSG(channel, message) => {
-                let Some(nick) = actual_message.source_nickname() else {
-                    continue;
-                };
+            message => message?,
+        };
+        nick_tracker.handle_message(&message)?;
+        caps.handle_message(&message)?;
 
-                let nick = nick.to_string();
-                if config.ignored_irc_users.contains(&nick.to_string()) {
-                    continue;
+        let from_us = message
+            .source_nickname()
+            .is_some_and(|source| nick_tracker.is_current(source));
+        if delivery.handle_message(&message, from_us)
+            || names.handle_message(&message, from_us)
+            || whois.handle_message(&message)
+        {
+            continue;
+        }
+
+        if let Err(e) = handle_message(&ctx, &http, guild, message, from_us).await {
+            println!("LOG: Could not handle irc message: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Relays or answers one message from irc. Failing here, say because the database could not be
+/// reached, only loses that message.
+async fn handle_message(
+    ctx: &IrcContext,
+    http: &Http,
+    guild: GuildId,
+    message: Message,
+    from_us: bool,
+) -> crate::Result<()> {
+    let IrcContext {
+        users,
+        config,
+        senders,
+        nick_tracker,
+        accounts,
+        ignores,
+        direction,
+        opt_outs,
+        echoes,
+        filters,
+        ..
+    } = ctx;
+    let private_message_expiry = Duration::from_secs(config.private_message_expiry);
+
+    if let Some(AccountChange {
+        nick,
+        account: Some(account),
+    }) = accounts.handle_message(&message, from_us)?
+        && users.revoke_if_account_changed(&nick, &account).await?
+    {
+        println!("LOG: Revoked verified link for {nick} after it logged in to account {account}");
+    }
+
+    let actual_message = message.clone();
+
+    match message.command {
+        irc::proto::Command::PRIVMSG(channel, message) => {
+            let Some(nick) = actual_message.source_nickname() else {
+                return Ok(());
+            };
+
+            // Our own messages only show up here as echoes, which are never relayed, and
+            // neither is anything from other relays or what they copied from us
+            if from_us || echo::is_relay_nick(config, nick) {
+                return Ok(());
+            }
+
+            let nick = nick.to_string();
+            let account = irc_caps::message_account(&actual_message)
+                .map(String::from)
+                .or_else(|| accounts.account(&nick));
+            let hostmask = match &actual_message.prefix {
+                Some(Prefix::Nickname(nick, user, host)) if !host.is_empty() => {
+                    Some(format!("{nick}!{user}@{host}"))
                 }
+                _ => None,
+            };
+            if ignores.is_irc_ignored(&nick, hostmask.as_deref(), account.as_deref()) {
+                return Ok(());
+            }
 
-                let username: String;
-                let message = message.clone();
+            let username: String;
+            let message = irc_caps::mark_delayed(
+                message.clone(),
+                &actual_message,
+                Duration::from_secs(config.irc_delay_threshold),
+            );
 
-                let stored_user = lookup_nick_in_database(&database_pool, &nick).await;
+            let stored_user = users.find_irc_user(&nick, account.as_deref()).await?;
 
-                let user_in_discord = find_member_for_nick(&http, guild, nick.clone()).await;
+            let user_in_discord = find_member_for_nick(http, guild, nick.clone()).await;
 
-                if channel == config.irc_channel.clone() {
-                    username = if let Some(user) = &stored_user {
-                        // If the user is verified to be a discord user use that avatar
-                        if user.verified {
-                            user.discord_name
-                                .clone()
-                                .expect("Could not find name on verified user")
-                        } else {
-                            if user_in_discord.is_some() {
-                                let guild_member = user_in_discord.clone().unwrap();
-                                guild_member.nick.unwrap_or(guild_member.user.name)
-                            } else {
-                                nick.clone()
-                            }
+            if channel == config.irc_channel.clone() {
+                if !direction.get().to_discord() || echoes.is_irc_echo(&message) {
+                    return Ok(());
+                }
+
+                match opt_outs.irc_status(&nick, account.as_deref()).await? {
+                    OptOutStatus::Bridged => {}
+                    OptOutStatus::OptedOut { notify } => {
+                        if notify {
+                            let notice = format!(
+                                "You opted out of the Discord bridge, so your messages in {} \
+                                 are not sent to Discord. Send me optin to have them bridged \
+                                 again.",
+                                config.irc_channel
+                            );
+                            senders
+                                .irc
+                                .send(crate::IrcRequest::SendMessage {
+                                    to: nick.clone(),
+                                    message: notice,
+                                })
+                                .await?;
                         }
-                    } else {
-                        nick.clone()
-                    };
+                        return Ok(());
+                    }
+                }
 
-                    // ignore the bridge users
-                    if username.contains("discord") {
-                        continue;
+                // Filtered first, so a dropped message does not change the avatar
+                let message = match filters.apply(Direction::IrcToDiscord, &message) {
+                    Filtered::Drop { rule } => {
+                        println!("LOG: Filter rule {rule} dropped a message from {nick}");
+                        return Ok(());
                     }
+                    Filtered::Relay { text, flagged } => {
+                        if !flagged.is_empty() {
+                            filters::flag_to_admin_users(http, config, &flagged, &nick, &text)
+                                .await;
+                        }
+                        text
+                    }
+                };
 
-                    senders
-                        .discord
-                        .send(DiscordRequest::SetAvatar {
-                            avatar_url: select_avatar_for_user(
-                                &database_pool,
-                                &http,
-                                guild,
-                                nick.clone(),
-                            )
-                            .await,
-                        })
-                        .await?;
+                username = if let Some(user) = &stored_user {
+                    // If the user is verified to be a discord user use that avatar
+                    if user.verified {
+                        user.discord_name
+                            .clone()
+                            .expect("Could not find name on verified user")
+                    } else {
+                        if user_in_discord.is_some() {
+                            let guild_member = user_in_discord.clone().unwrap();
+                            guild_member.nick.unwrap_or(guild_member.user.name)
+                        } else {
+                            nick.clone()
+                        }
+                    }
+                } else {
+                    nick.clone()
+                };
 
-                    senders
-                        .discord
-                        .send(DiscordRequest::SendMessage {
-                            alias: username,
-                            message,
-                        })
-                        .await?;
-                } else if channel == config.irc_nick {
-                    let mut args: Vec<&str> = message.split_whitespace().collect();
-                    let mut args_with_command_name = vec!["bridge"];
-                    args_with_command_name.append(&mut args);
-                    let command = match IrcBotCommand::try_parse_from(args_with_command_name.iter())
-                    {
-                        Err(e) => {
-                            let pmsg_user = |msg: String| async {
+                senders
+                    .discord
+                    .send(DiscordRequest::SetAvatar {
+                        avatar_url: select_avatar_for_user(
+                            users.as_ref(),
+                            http,
+                            guild,
+                            nick.clone(),
+                            account.as_deref(),
+                        )
+                        .await,
+                    })
+                    .await?;
+
+                let (message, mentioned) =
+                    mentions::resolve_mentions(users.as_ref(), &message).await?;
+                senders
+                    .discord
+                    .send(DiscordRequest::SendMessage {
+                        alias: webhook_username(&username, &config.webhook_name_suffix),
+                        message,
+                        mentions: mentioned,
+                    })
+                    .await?;
+            } else if nick_tracker.is_current(&channel) {
+                let mut args: Vec<&str> = message.split_whitespace().collect();
+                let mut args_with_command_name = vec!["bridge"];
+                args_with_command_name.append(&mut args);
+                let command = match IrcBotCommand::try_parse_from(args_with_command_name.iter()) {
+                    Err(e) => {
+                        // Anything that is not a command is a reply to whoever last used /msg
+                        if let Some(discord_id) = users
+                            .find_private_route_by_nick(&nick, private_message_expiry)
+                            .await?
+                        {
+                            let refusal = private_reply_refusal(
+                                ignores,
+                                opt_outs,
+                                &nick,
+                                account.as_deref(),
+                                discord_id,
+                            )?;
+                            if let Some(refusal) = refusal {
                                 senders
                                     .irc
                                     .send(crate::IrcRequest::SendMessage {
                                         to: nick.clone(),
-                                        message: msg,
+                                        message: refusal.to_string(),
                                     })
-                                    .await
-                            };
-                            println!("{e}");
-
-                            pmsg_user("Error, unknown command".into()).await?;
-                            pmsg_user("Valid commands are: ".into()).await?;
-                            pmsg_user("> avatar gravatar {email}".into()).await?;
-                            pmsg_user("> avatar reset".into()).await?;
-                            pmsg_user("> avatar url {url}".into()).await?;
-                            continue;
+                                    .await?;
+                                return Ok(());
+                            }
+
+                            users.open_private_route(&nick, discord_id).await?;
+                            notify_discord_user(
+                                http,
+                                discord_id,
+                                format!("**{}**: {message}", escape_markdown(&nick)),
+                            )
+                            .await;
+                            return Ok(());
                         }
-                        Ok(command) => command,
-                    };
 
-                    handle_irc_bot_command(command, stored_user, &database_pool, nick).await?
+                        let pmsg_user = |msg: String| async {
+                            senders
+                                .irc
+                                .send(crate::IrcRequest::SendMessage {
+                                    to: nick.clone(),
+                                    message: msg,
+                                })
+                                .await
+                        };
+                        println!("{e}");
+
+                        pmsg_user("Error, unknown command".into()).await?;
+                        pmsg_user("Valid commands are: ".into()).await?;
+                        pmsg_user("> avatar gravatar {email}".into()).await?;
+                        pmsg_user("> avatar reset".into()).await?;
+                        pmsg_user("> avatar url {url}".into()).await?;
+                        pmsg_user("> connect {code}".into()).await?;
+                        pmsg_user("> unlink".into()).await?;
+                        pmsg_user("> optout, optin".into()).await?;
+                        pmsg_user("> whois {nick|@discord_name}".into()).await?;
+                        pmsg_user("Admins: links, reload, pause [direction], resume".into())
+                            .await?;
+                        pmsg_user("> mentions allow|deny".into()).await?;
+                        pmsg_user("> avatar --for {nick} ...".into()).await?;
+                        pmsg_user("> link {nick} {discord_id} [account]".into()).await?;
+                        pmsg_user("> ignore add|remove {kind} {value}, ignore list".into()).await?;
+                        return Ok(());
+                    }
+                    Ok(command) => command,
+                };
+
+                let caller = Caller {
+                    nick,
+                    hostmask,
+                    account,
+                };
+                // A command that fails is answered, it must not stop the bridge
+                if let Err(e) =
+                    handle_irc_bot_command(ctx, http, command, stored_user, &caller, &message).await
+                {
+                    println!("LOG: Command from {} failed: {e}", caller.nick);
+                    senders
+                        .irc
+                        .send(crate::IrcRequest::SendMessage {
+                            to: caller.nick,
+                            message: format!("Could not do that: {e}"),
+                        })
+                        .await?;
                 }
             }
+        }
 
-            _ => {
-                println!("Unrecognized message {:?}", message)
-            }
+        _ => {
+            println!("Unrecognized message {:?}", message)
         }
     }
 
@@ -264,77 +499,259 @@ pub async fn irc_receiver(
 }
 
 async fn handle_irc_bot_command(
+    ctx: &IrcContext,
+    http: &Http,
     command: IrcBotCommand,
-    stored_user: Option<UserInfo>,
-    database_pool: &SqlitePool,
-    nick: String,
+    stored_user: Option<User>,
+    caller: &Caller,
+    text: &str,
 ) -> crate::Result<()> {
+    let users = ctx.users.as_ref();
+    let config = &ctx.config;
+    let Caller {
+        nick,
+        hostmask,
+        account,
+    } = caller;
+    let pmsg_user = |msg: String| async {
+        ctx.senders
+            .irc
+            .send(crate::IrcRequest::SendMessage {
+                to: nick.clone(),
+                message: msg,
+            })
+            .await
+    };
+
+    if command.requires_admin() {
+        let allowed = permissions::is_irc_admin(config, hostmask.as_deref(), account.as_deref());
+        let actor = match &account {
+            Some(account) => format!("{} ({account})", hostmask.as_ref().unwrap_or(nick)),
+            None => hostmask.clone().unwrap_or(nick.clone()),
+        };
+        permissions::audit(users, "irc", &actor, text, allowed).await;
+
+        if !allowed {
+            pmsg_user("Only bridge admins can do that".into()).await?;
+            return Ok(());
+        }
+    }
+
     match command {
-        IrcBotCommand::Avatar { command } => match command {
-            AvatarCommand::Url { url } => {
-                if let Some(_) = stored_user {
-                    sqlx::query!("UPDATE users SET avatar = ?1 WHERE ircnick = ?2", url, nick)
-                        .execute(database_pool)
-                        .await?;
-                } else {
-                    sqlx::query!(
-                        "INSERT INTO users ( ircnick, avatar) VALUES ( ?1,?2 )",
-                        nick,
-                        url
-                    )
-                    .execute(database_pool)
-                    .await?;
+        IrcBotCommand::Avatar { target, command } => {
+            // A verified user keeps their avatar on the nick they linked, whatever nick they use
+            let avatar_nick = match target {
+                Some(target) => target,
+                None => stored_user
+                    .map(|user| user.irc_nick)
+                    .unwrap_or(nick.clone()),
+            };
+            match command {
+                AvatarCommand::Url { url } => users.set_avatar(&avatar_nick, Some(&url)).await?,
+                AvatarCommand::Gravatar { email } => {
+                    let avatar_url = make_gravatar_url(email);
+                    users.set_avatar(&avatar_nick, Some(&avatar_url)).await?
                 }
+                AvatarCommand::Reset => users.set_avatar(&avatar_nick, None).await?,
             }
-            AvatarCommand::Gravatar { email } => {
-                let avatar_url = make_gravatar_url(email);
-                if let Some(_) = stored_user {
-                    sqlx::query!(
-                        "UPDATE users SET avatar = ?1 WHERE ircnick = ?2",
-                        avatar_url,
-                        nick
-                    )
-                    .execute(database_pool)
+        }
+        IrcBotCommand::Connect { code } => {
+            // Anyone can use an unregistered nick, so links are tied to the services account
+            let Some(account) = account else {
+                pmsg_user("You need to be logged in to NickServ to connect your account".into())
                     .await?;
-                } else {
-                    sqlx::query!(
-                        "INSERT INTO users (ircnick, avatar) VALUES ( ?1,?2 )",
-                        nick,
-                        avatar_url
+                return Ok(());
+            };
+
+            match ctx.pending_links.redeem(nick, &code) {
+                Ok(link) => {
+                    let linked = users
+                        .upsert_link(
+                            &link.irc_nick,
+                            link.discord_id,
+                            &link.discord_nick,
+                            &link.discord_name,
+                        )
+                        .await?;
+                    if !linked {
+                        pmsg_user(format!(
+                            "{} is already connected to another discord user, send unlink first",
+                            link.irc_nick
+                        ))
+                        .await?;
+                        return Ok(());
+                    }
+                    users
+                        .verify(&link.irc_nick, link.discord_id, account)
+                        .await?;
+
+                    println!(
+                        "LOG: Connected {nick} ({account}) to discord user {}",
+                        link.discord_id
+                    );
+                    pmsg_user("Your account is now connected to Discord".into()).await?;
+                    notify_discord_user(
+                        http,
+                        link.discord_id,
+                        format!("You are now connected to {} on irc", link.irc_nick),
                     )
-                    .execute(database_pool)
-                    .await?;
+                    .await;
+                }
+                Err(RedeemError::Invalid) => {
+                    pmsg_user("That code is invalid or has expired".into()).await?;
+                }
+                Err(RedeemError::RateLimited(cancelled)) => {
+                    pmsg_user("Too many attempts, please try again later".into()).await?;
+                    for link in cancelled {
+                        notify_discord_user(
+                            http,
+                            link.discord_id,
+                            format!(
+                                "Too many wrong codes were tried for {} on irc, so your code was \
+                                 cancelled. Use /connect_user again to get a new one",
+                                link.irc_nick
+                            ),
+                        )
+                        .await;
+                    }
                 }
             }
-            AvatarCommand::Reset => {
-                if let Some(user) = stored_user && user.avatar.is_some() {
-                                    sqlx::query!(
-                                        "UPDATE users SET avatar = ?1 WHERE ircnick = ?2",
-                                        None::<String>,
-                                        nick
-                                    ).execute(database_pool)
-                                    .await
-                                    ?;
-                                }
+        }
+        IrcBotCommand::Unlink => {
+            if users.unlink_irc(nick, account.as_deref()).await? {
+                pmsg_user("Your nick is no longer linked to Discord".into()).await?;
+            } else {
+                pmsg_user("Your nick is not linked to Discord".into()).await?;
+            }
+        }
+        IrcBotCommand::Optout => {
+            if ctx.opt_outs.opt_out_irc(nick, account.as_deref()).await? {
+                pmsg_user(
+                    "Your messages will no longer be bridged to Discord, send optin to undo this"
+                        .into(),
+                )
+                .await?;
+            } else {
+                pmsg_user("You have already opted out of the bridge".into()).await?;
+            }
+        }
+        IrcBotCommand::Optin => {
+            if ctx.opt_outs.opt_in_irc(nick, account.as_deref()).await? {
+                pmsg_user("Your messages will be bridged to Discord again".into()).await?;
+            } else {
+                pmsg_user("You had not opted out of the bridge".into()).await?;
             }
+        }
+        IrcBotCommand::Whois { target } => match linking::find_link(users, &target).await? {
+            Some(link) => pmsg_user(link.describe()).await?,
+            None => pmsg_user(format!("No link found for {target}")).await?,
         },
-        IrcBotCommand::Connect { discord_id } => {
-            if let Some(user) = stored_user {
-                if user.discord_nick == Some(discord_id) {
-                    sqlx::query!(
-                        "UPDATE users SET verified = ?1 WHERE ircnick = ?2",
-                        true,
-                        nick
-                    )
-                    .execute(database_pool)
-                    .await?;
-                }
+        IrcBotCommand::Links => {
+            let links = users.all_linked().await?;
+            if links.is_empty() {
+                pmsg_user("There are no linked users".into()).await?;
+            }
+            for link in links {
+                pmsg_user(link.describe()).await?;
+            }
+        }
+        IrcBotCommand::Ignore { command } => {
+            for line in ctx.ignores.handle_command(command).await? {
+                pmsg_user(line).await?;
             }
         }
+        IrcBotCommand::Reload => {
+            // Whatever could not be reloaded keeps what it had
+            let ignores = ctx.ignores.reload().await;
+            let rules = ctx.filters.reload().await;
+            let reply = match (ignores, rules) {
+                (Ok(ignores), Ok(rules)) => {
+                    format!("Reloaded {ignores} ignores and {rules} filter rules")
+                }
+                (Err(e), _) => format!("Could not reload ignores: {e}"),
+                (_, Err(e)) => format!("Could not reload filter rules: {e}"),
+            };
+            pmsg_user(reply).await?;
+        }
+        IrcBotCommand::Pause { .. } | IrcBotCommand::Resume => {
+            let direction = match command {
+                IrcBotCommand::Pause { direction } => direction,
+                _ => Direction::Both,
+            };
+            let reply = match ctx.direction.set(direction).await {
+                Ok(()) => direction.describe().to_string(),
+                Err(e) => format!("Could not change the bridge direction: {e}"),
+            };
+            pmsg_user(reply).await?;
+        }
+        IrcBotCommand::Mentions { policy } => {
+            ctx.mentions.set(policy).await?;
+            pmsg_user(policy.describe().into()).await?;
+        }
+        IrcBotCommand::Link {
+            nick: irc_nick,
+            discord_id,
+            account: irc_account,
+        } => {
+            let user = match http.get_user(discord_id).await {
+                Ok(user) => user,
+                Err(e) => {
+                    pmsg_user(format!("Could not find discord user {discord_id}: {e}")).await?;
+                    return Ok(());
+                }
+            };
+            let linked = linking::force_link(
+                users,
+                &irc_nick,
+                discord_id,
+                &user.name,
+                &user.name,
+                irc_account.as_deref(),
+            )
+            .await;
+            let reply = match linked {
+                Ok(()) => format!("Linked {irc_nick} to discord user {}", user.name),
+                Err(e) => format!("Could not link {irc_nick}: {e}"),
+            };
+            pmsg_user(reply).await?;
+        }
     }
     Ok(())
 }
 
+/// Sends a Discord user a direct message, logging rather than failing if they do not accept them
+/// Why a private reply from irc is not passed on. Opted out or ignored on either side, the reply
+/// goes nowhere.
+fn private_reply_refusal(
+    ignores: &IgnoreList,
+    opt_outs: &OptOuts,
+    nick: &str,
+    account: Option<&str>,
+    discord_id: u64,
+) -> crate::Result<Option<&'static str>> {
+    Ok(if opt_outs.irc_opted_out(nick, account)? {
+        Some(
+            "You opted out of the Discord bridge, so your reply was not sent. Send me optin to \
+             have it bridged again.",
+        )
+    } else if ignores.is_discord_ignored(discord_id) || opt_outs.discord_opted_out(discord_id)? {
+        Some("They can no longer be reached through the bridge, so your reply was not sent")
+    } else {
+        None
+    })
+}
+
+async fn notify_discord_user(http: &Http, discord_id: u64, message: String) {
+    let result = match UserId(discord_id).create_dm_channel(http).await {
+        Ok(channel) => channel.say(http, message).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        println!("LOG: Could not send a direct message to discord user {discord_id}: {e}");
+    }
+}
+
 async fn find_member_for_nick(http: &Http, guild: GuildId, nick: String) -> Option<Member> {
     guild
         .search_members(http, nick.as_str(), None)
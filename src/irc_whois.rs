@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use irc::proto::{Command, Message, Response};
+use tokio::sync::oneshot;
+
+use crate::irc_nick::fold_nick;
+
+/// What the server told us about a nick
+#[derive(Clone, Debug, Default)]
+pub struct Whois {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub realname: String,
+    pub server: Option<String>,
+    pub channels: Vec<String>,
+    pub account: Option<String>,
+    /// Seconds since the user last spoke, only sent when we ask their own server
+    pub idle: Option<u64>,
+}
+
+/// Requests for a nick that are still waiting for an answer, oldest first
+type Waiting = VecDeque<oneshot::Sender<Option<Whois>>>;
+
+/// Collects the numerics of WHOIS replies and hands the result to whoever asked once
+/// RPL_ENDOFWHOIS arrives. The reply is None when the nick is not on the server, which the server
+/// may say with ERR_NOSUCHNICK or, when asked for the nick's own server, ERR_NOSUCHSERVER and no
+/// RPL_ENDOFWHOIS at all.
+#[derive(Clone, Debug, Default)]
+pub struct WhoisCollector {
+    waiting: Arc<Mutex<HashMap<String, Waiting>>>,
+    partial: Arc<Mutex<HashMap<String, Whois>>>,
+    /// Nicks answered by ERR_NOSUCHNICK, whose RPL_ENDOFWHOIS may still follow
+    answered: Arc<Mutex<HashSet<String>>>,
+}
+
+impl WhoisCollector {
+    /// Registers a request for a nick, to be called just before sending WHOIS
+    pub fn expect(&self, nick: &str, reply: oneshot::Sender<Option<Whois>>) {
+        self.waiting
+            .lock()
+            .expect("whois lock poisoned")
+            .entry(fold_nick(nick))
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Handles WHOIS replies, returning true if the message was one
+    pub fn handle_message(&self, message: &Message) -> bool {
+        let mut partial = self.partial.lock().expect("whois lock poisoned");
+        let mut entry = |nick: &str| {
+            partial
+                .entry(fold_nick(nick))
+                .or_insert_with(|| Whois {
+                    nick: nick.to_string(),
+                    ..Default::default()
+                })
+                .clone()
+        };
+
+        let update = match &message.command {
+            // <us> <nick> <user> <host> * :<realname>
+            Command::Response(Response::RPL_WHOISUSER, args) => match args.as_slice() {
+                [_, nick, user, host, _, realname] => {
+                    self.answered
+                        .lock()
+                        .expect("whois lock poisoned")
+                        .remove(&fold_nick(nick));
+                    Whois {
+                        user: user.clone(),
+                        host: host.clone(),
+                        realname: realname.clone(),
+                        ..entry(nick)
+                    }
+                }
+                _ => return true,
+            },
+            // <us> <nick> <server> :<server info>
+            Command::Response(Response::RPL_WHOISSERVER, args) => match args.as_slice() {
+                [_, nick, server, ..] => Whois {
+                    server: Some(server.clone()),
+                    ..entry(nick)
+                },
+                _ => return true,
+            },
+            // <us> <nick> :<channels>, may be split over several lines
+            Command::Response(Response::RPL_WHOISCHANNELS, args) => match args.as_slice() {
+                [_, nick, channels] => {
+                    let mut whois = entry(nick);
+                    whois
+                        .channels
+                        .extend(channels.split_whitespace().map(String::from));
+                    whois
+                }
+                _ => return true,
+            },
+            // <us> <nick> <seconds idle> <signon> :seconds idle, signon time
+            Command::Response(Response::RPL_WHOISIDLE, args) => match args.as_slice() {
+                [_, nick, idle, ..] => Whois {
+                    idle: idle.parse().ok(),
+                    ..entry(nick)
+                },
+                _ => return true,
+            },
+            // RPL_WHOISACCOUNT, <us> <nick> <account> :is logged in as
+            Command::Raw(code, args) if code == "330" => match args.as_slice() {
+                [_, nick, account, ..] => Whois {
+                    account: Some(account.clone()),
+                    ..entry(nick)
+                },
+                _ => return true,
+            },
+            // <us> <nick> :End of /WHOIS list
+            Command::Response(Response::RPL_ENDOFWHOIS, args) => {
+                let Some(nick) = args.get(1).map(|nick| fold_nick(nick)) else {
+                    return true;
+                };
+                // Without RPL_WHOISUSER the nick was not found
+                let whois = partial.remove(&nick).filter(|whois| !whois.user.is_empty());
+                let answered = self
+                    .answered
+                    .lock()
+                    .expect("whois lock poisoned")
+                    .remove(&nick);
+                if !answered {
+                    self.finish(&nick, whois);
+                }
+                return true;
+            }
+            // <us> <nick> :No such nick/channel, also sent when a PRIVMSG finds nobody
+            Command::Response(Response::ERR_NOSUCHNICK, args) => {
+                let Some(nick) = args.get(1).map(|nick| fold_nick(nick)) else {
+                    return false;
+                };
+                partial.remove(&nick);
+                if self.finish(&nick, None) {
+                    self.answered
+                        .lock()
+                        .expect("whois lock poisoned")
+                        .insert(nick);
+                }
+                return true;
+            }
+            // <us> <server> :No such server, the server being the nick we asked about
+            Command::Response(Response::ERR_NOSUCHSERVER, args) => {
+                let Some(nick) = args.get(1).map(|nick| fold_nick(nick)) else {
+                    return false;
+                };
+                partial.remove(&nick);
+                self.finish(&nick, None);
+                return true;
+            }
+            Command::Response(Response::RPL_WHOISOPERATOR | Response::RPL_WHOISCERTFP, _) => {
+                return true;
+            }
+            _ => return false,
+        };
+
+        partial.insert(fold_nick(&update.nick), update);
+        true
+    }
+
+    /// Hands the answer to the oldest request for the nick, returning false if none was waiting
+    fn finish(&self, nick: &str, whois: Option<Whois>) -> bool {
+        let mut waiting = self.waiting.lock().expect("whois lock poisoned");
+        // Skip requests whose caller already gave up waiting
+        while let Some(reply) = waiting.get_mut(nick).and_then(VecDeque::pop_front) {
+            if reply.send(whois.clone()).is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Hides the identifying part of a host. Cloaks (anything with a /) are already masked by the
+/// network, addresses lose their host part and hostnames keep only their domain.
+pub fn mask_host(host: &str) -> String {
+    if host.contains('/') {
+        return host.to_string();
+    }
+
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, ..] = ip.octets();
+            format!("{a}.{b}.*.*")
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let [a, b, ..] = ip.segments();
+            format!("{a:x}:{b:x}:*")
+        }
+        Err(_) => {
+            let labels = host.split('.').collect::<Vec<_>>();
+            match labels.len() {
+                0..=2 => "*".to_string(),
+                n => format!("*.{}", labels[n - 2..].join(".")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(whois: &WhoisCollector, line: &str) -> bool {
+        whois.handle_message(&line.parse().unwrap())
+    }
+
+    #[test]
+    fn replies_are_collected_until_the_end() {
+        let whois = WhoisCollector::default();
+        let (reply, mut answer) = oneshot::channel();
+        whois.expect("Alice", reply);
+
+        handle(&whois, ":server 311 bridge alice a host * :Alice A");
+        handle(&whois, ":server 319 bridge alice :#bridge @#ops");
+        handle(
+            &whois,
+            ":server 330 bridge alice alice_acct :is logged in as",
+        );
+        assert!(answer.try_recv().is_err());
+        handle(&whois, ":server 318 bridge alice :End of /WHOIS list");
+
+        let answer = answer.try_recv().unwrap().unwrap();
+        assert_eq!(answer.host, "host");
+        assert_eq!(answer.channels, ["#bridge", "@#ops"]);
+        assert_eq!(answer.account.as_deref(), Some("alice_acct"));
+    }
+
+    #[test]
+    fn no_such_server_means_not_on_irc() {
+        let whois = WhoisCollector::default();
+        let (reply, mut answer) = oneshot::channel();
+        whois.expect("gone", reply);
+
+        handle(&whois, ":server 402 bridge gone :No such server");
+        assert!(answer.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn no_such_nick_means_not_on_irc() {
+        let whois = WhoisCollector::default();
+        let (first, mut first_answer) = oneshot::channel();
+        let (second, mut second_answer) = oneshot::channel();
+        whois.expect("gone", first);
+        whois.expect("gone", second);
+
+        handle(&whois, ":server 401 bridge gone :No such nick/channel");
+        assert!(first_answer.try_recv().unwrap().is_none());
+        // The end of the first reply is not taken for the second
+        handle(&whois, ":server 318 bridge gone :End of /WHOIS list");
+        assert!(second_answer.try_recv().is_err());
+
+        handle(&whois, ":server 311 bridge gone g host * :Back");
+        handle(&whois, ":server 318 bridge gone :End of /WHOIS list");
+        assert_eq!(second_answer.try_recv().unwrap().unwrap().realname, "Back");
+    }
+
+    #[test]
+    fn no_such_nick_without_a_request_is_left_alone() {
+        let whois = WhoisCollector::default();
+        handle(&whois, ":server 401 bridge gone :No such nick/channel");
+
+        let (reply, mut answer) = oneshot::channel();
+        whois.expect("gone", reply);
+        handle(&whois, ":server 402 bridge gone :No such server");
+        assert!(answer.try_recv().unwrap().is_none());
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::irc_nick::fold_nick;
+use crate::user_store::{User, UserStore};
+use crate::Result;
+
+/// How long a code from `/connect_user` can be used for
+pub const CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// How many codes an irc nick may try within `ATTEMPT_WINDOW`
+const MAX_ATTEMPTS: usize = 5;
+const ATTEMPT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+const CODE_LENGTH: usize = 8;
+/// Letters and digits that cannot be mistaken for each other
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+#[derive(Clone, Debug)]
+pub struct PendingLink {
+    pub discord_id: u64,
+    pub discord_nick: String,
+    pub discord_name: String,
+    pub irc_nick: String,
+    created_at: Instant,
+}
+
+/// Why a code could not be redeemed
+#[derive(Debug)]
+pub enum RedeemError {
+    /// The nick has tried too many codes recently. Carries the links that were cancelled because
+    /// of it, so the Discord users can be told.
+    RateLimited(Vec<PendingLink>),
+    /// The code does not exist, has expired or was issued for another nick
+    Invalid,
+}
+
+/// Codes handed out by `/connect_user`, waiting for the irc user to send them to the bot
+#[derive(Clone, Debug, Default)]
+pub struct PendingLinks {
+    links: Arc<Mutex<HashMap<String, PendingLink>>>,
+    attempts: Arc<Mutex<HashMap<String, Vec<Instant>>>>,
+}
+
+impl PendingLinks {
+    /// Creates a new code for linking the Discord user to the irc nick, replacing any earlier
+    /// code the Discord user had. Nothing is stored until the code is redeemed.
+    pub fn create(
+        &self,
+        discord_id: u64,
+        discord_nick: &str,
+        discord_name: &str,
+        irc_nick: &str,
+    ) -> String {
+        let mut rng = rand::thread_rng();
+        let code: String = (0..CODE_LENGTH)
+            .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+            .collect();
+
+        let mut links = self.links.lock().expect("links lock poisoned");
+        links.retain(|_, link| link.discord_id != discord_id && !is_expired(link));
+        links.insert(
+            code.clone(),
+            PendingLink {
+                discord_id,
+                discord_nick: discord_nick.to_string(),
+                discord_name: discord_name.to_string(),
+                irc_nick: irc_nick.to_string(),
+                created_at: Instant::now(),
+            },
+        );
+        code
+    }
+
+    /// Uses up a code sent by the irc nick, returning the link it was for
+    pub fn redeem(
+        &self,
+        irc_nick: &str,
+        code: &str,
+    ) -> std::result::Result<PendingLink, RedeemError> {
+        let folded = fold_nick(irc_nick);
+        let mut links = self.links.lock().expect("links lock poisoned");
+        links.retain(|_, link| !is_expired(link));
+
+        let mut attempts = self.attempts.lock().expect("attempts lock poisoned");
+        let recent = attempts.entry(folded.clone()).or_default();
+        recent.retain(|attempt| attempt.elapsed() < ATTEMPT_WINDOW);
+        if recent.len() >= MAX_ATTEMPTS {
+            // Someone is guessing, so throw away every code for this nick
+            let (cancelled, kept): (HashMap<_, _>, HashMap<_, _>) = links
+                .drain()
+                .partition(|(_, link)| fold_nick(&link.irc_nick) == folded);
+            *links = kept;
+            return Err(RedeemError::RateLimited(cancelled.into_values().collect()));
+        }
+        recent.push(Instant::now());
+
+        let code = code.to_uppercase();
+        match links.get(&code) {
+            Some(link) if fold_nick(&link.irc_nick) == folded => {
+                attempts.remove(&folded);
+                Ok(links.remove(&code).expect("link was just found"))
+            }
+            _ => Err(RedeemError::Invalid),
+        }
+    }
+}
+
+fn is_expired(link: &PendingLink) -> bool {
+    link.created_at.elapsed() >= CODE_LIFETIME
+}
+
+/// Finds a link by irc nick, by Discord name when the target starts with `@`, or by Discord
+/// user id for a mention like `<@1234>`
+pub async fn find_link(users: &dyn UserStore, target: &str) -> Result<Option<User>> {
+    if let Some(id) = target
+        .strip_prefix("<@")
+        .and_then(|id| id.strip_suffix('>'))
+        .and_then(|id| id.trim_start_matches('!').parse::<u64>().ok())
+    {
+        return users.find_by_discord_id(id).await;
+    }
+
+    match target.strip_prefix('@') {
+        Some(name) => users.find_by_discord_name(name).await,
+        None => users.find_by_nick(target).await,
+    }
+}
+
+/// Links an irc nick to a Discord user without a code, taking the nick from whoever had it. The
+/// link is only verified when the services account is given.
+pub async fn force_link(
+    users: &dyn UserStore,
+    irc_nick: &str,
+    discord_id: u64,
+    discord_nick: &str,
+    discord_name: &str,
+    irc_account: Option<&str>,
+) -> Result<()> {
+    users.unlink_irc(irc_nick, None).await?;
+    users
+        .upsert_link(irc_nick, discord_id, discord_nick, discord_name)
+        .await?;
+    if let Some(account) = irc_account {
+        users.verify(irc_nick, discord_id, account).await?;
+    }
+    Ok(())
+}
@@ -1,25 +1,62 @@
 #![feature(let_chains, unboxed_closures, async_closure)]
-use clap::Parser;
-use irc::{
-    client::Sender,
-    proto::{Command, Message, Prefix},
-};
-use irc_side::IrcResponseCallback;
+use clap::{ArgAction, Parser};
+use direction::{Direction, PairDirection};
+use echo::EchoFilter;
+use encoding_rs::Encoding;
+use filters::Filters;
+use ignores::IgnoreList;
+use irc::{client::Sender, proto::Command};
+use irc_accounts::AccountTracker;
+use irc_caps::{Capabilities, DeliveryTracker};
+use irc_connection::Fingerprint;
+use irc_login::SaslMechanism;
+use irc_names::NamesCollector;
+use irc_nick::{nick_eq, GhostCommand, NickTracker};
+use irc_whois::{Whois, WhoisCollector};
+use linking::PendingLinks;
+use mentions::{MentionPolicy, PairMentions};
+use optout::OptOuts;
 use serenity::{
+    builder::{CreateApplicationCommandOption, CreateApplicationCommands, ParseValue},
     framework::StandardFramework,
     http::Http,
-    model::{prelude::application_command::ApplicationCommandInteraction, webhook::Webhook},
+    model::{
+        application::command::{Command as ApplicationCommand, CommandOptionType},
+        id::GuildId,
+        webhook::Webhook,
+    },
     prelude::*,
 };
-use sqlx::SqlitePool;
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use tokio::{
     select,
     sync::mpsc::{channel, Receiver},
+    sync::oneshot,
 };
 
+mod direction;
 mod discord;
+mod echo;
+mod filters;
+mod fnv;
+mod ignores;
+mod irc_accounts;
+mod irc_caps;
+mod irc_connection;
+mod irc_format;
+mod irc_login;
+mod irc_names;
+mod irc_nick;
+mod irc_sanitise;
 mod irc_side;
+mod irc_whois;
+mod linking;
+mod mentions;
+mod optout;
+mod permissions;
+mod user_store;
+mod webhook_name;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -27,15 +64,162 @@ pub struct Config {
     #[clap(env = "BRIDGE_IRC_NICK")]
     irc_nick: String,
 
+    /// Nicks to fall back to when the configured nick is in use
+    #[clap(
+        env = "BRIDGE_IRC_ALT_NICKS",
+        long = "irc_alt_nick",
+        value_delimiter = ','
+    )]
+    irc_alt_nicks: Vec<String>,
+
+    /// NickServ command used to free the configured nick when someone else holds it
+    #[clap(
+        env = "BRIDGE_IRC_GHOST_COMMAND",
+        long = "irc_ghost_command",
+        value_enum
+    )]
+    irc_ghost_command: Option<GhostCommand>,
+
+    /// Seconds between attempts to take the configured nick back
+    #[clap(
+        env = "BRIDGE_IRC_NICK_RECLAIM_INTERVAL",
+        long = "irc_nick_reclaim_interval",
+        default_value_t = 60
+    )]
+    irc_nick_reclaim_interval: u64,
+
     #[clap(env = "BRIDGE_IRC_HOST")]
     irc_host: String,
 
     #[clap(env = "BRIDGE_IRC_PORT")]
-    irc_port: String,
+    irc_port: u16,
+
+    #[clap(
+        env = "BRIDGE_IRC_TLS",
+        long = "irc_tls",
+        action = ArgAction::Set,
+        default_value_t = true
+    )]
+    irc_tls: bool,
+
+    #[clap(
+        env = "BRIDGE_IRC_TLS_ACCEPT_INVALID_CERTS",
+        long = "irc_tls_accept_invalid_certs",
+        action = ArgAction::Set,
+        default_value_t = false
+    )]
+    irc_tls_accept_invalid_certs: bool,
+
+    /// SHA-256 fingerprint the irc server certificate must match, replacing the usual validation
+    #[clap(env = "BRIDGE_IRC_TLS_FINGERPRINT", long = "irc_tls_fingerprint")]
+    irc_tls_fingerprint: Option<Fingerprint>,
+
+    /// Local address to connect to the irc server (or proxy) from
+    #[clap(env = "BRIDGE_IRC_BIND_ADDRESS", long = "irc_bind_address")]
+    irc_bind_address: Option<IpAddr>,
+
+    /// SOCKS5 proxy to connect to the irc server through
+    #[clap(env = "BRIDGE_IRC_PROXY_HOST", long = "irc_proxy_host")]
+    irc_proxy_host: Option<String>,
+
+    #[clap(
+        env = "BRIDGE_IRC_PROXY_PORT",
+        long = "irc_proxy_port",
+        default_value_t = 1080
+    )]
+    irc_proxy_port: u16,
+
+    #[clap(env = "BRIDGE_IRC_PROXY_USERNAME", long = "irc_proxy_username")]
+    irc_proxy_username: Option<String>,
+
+    #[clap(env = "BRIDGE_IRC_PROXY_PASSWORD", long = "irc_proxy_password")]
+    irc_proxy_password: Option<String>,
+
+    /// Encoding used to decode incoming lines that are not valid UTF-8, such as latin1 or
+    /// windows-1252
+    #[clap(
+        env = "BRIDGE_IRC_ENCODING",
+        long = "irc_encoding",
+        value_parser = irc_connection::parse_encoding,
+        default_value = "utf-8"
+    )]
+    irc_encoding: &'static Encoding,
+
+    /// Messages the server says were sent more than this many seconds ago are marked as delayed
+    #[clap(
+        env = "BRIDGE_IRC_DELAY_THRESHOLD",
+        long = "irc_delay_threshold",
+        default_value_t = 30
+    )]
+    irc_delay_threshold: u64,
+
+    /// Seconds without messages either way before replies to /msg stop going to the discord user
+    #[clap(
+        env = "BRIDGE_PRIVATE_MESSAGE_EXPIRY",
+        long = "private_message_expiry",
+        default_value_t = 3600
+    )]
+    private_message_expiry: u64,
+
+    /// Seconds of silence from the server before we send it a PING
+    #[clap(
+        env = "BRIDGE_IRC_PING_INTERVAL",
+        long = "irc_ping_interval",
+        default_value_t = 180
+    )]
+    irc_ping_interval: u32,
+
+    /// Seconds to wait for a PONG before treating the connection as dead
+    #[clap(
+        env = "BRIDGE_IRC_PING_TIMEOUT",
+        long = "irc_ping_timeout",
+        default_value_t = 20
+    )]
+    irc_ping_timeout: u32,
 
     #[clap(env = "BRIDGE_IRC_CHANNEL")]
     irc_channel: String,
 
+    /// Password sent to the irc server with PASS before registering
+    #[clap(env = "BRIDGE_IRC_PASSWORD", long = "irc_password")]
+    irc_password: Option<String>,
+
+    /// Username (ident) for the bridge, defaults to the nick
+    #[clap(env = "BRIDGE_IRC_USERNAME", long = "irc_username")]
+    irc_username: Option<String>,
+
+    /// Realname for the bridge, defaults to the nick
+    #[clap(env = "BRIDGE_IRC_REALNAME", long = "irc_realname")]
+    irc_realname: Option<String>,
+
+    #[clap(
+        env = "BRIDGE_IRC_SASL_MECHANISM",
+        long = "irc_sasl_mechanism",
+        value_enum
+    )]
+    irc_sasl_mechanism: Option<SaslMechanism>,
+
+    /// Account to authenticate as with SASL PLAIN, defaults to the nick
+    #[clap(env = "BRIDGE_IRC_SASL_USERNAME", long = "irc_sasl_username")]
+    irc_sasl_username: Option<String>,
+
+    #[clap(env = "BRIDGE_IRC_SASL_PASSWORD", long = "irc_sasl_password")]
+    irc_sasl_password: Option<String>,
+
+    /// PKCS#12 archive holding the client certificate used for SASL EXTERNAL
+    #[clap(env = "BRIDGE_IRC_CLIENT_CERT", long = "irc_client_cert")]
+    irc_client_cert: Option<String>,
+
+    #[clap(
+        env = "BRIDGE_IRC_CLIENT_CERT_PASSWORD",
+        long = "irc_client_cert_password"
+    )]
+    irc_client_cert_password: Option<String>,
+
+    /// Password to IDENTIFY to NickServ with when SASL is not configured
+    #[clap(env = "BRIDGE_IRC_NICKSERV_PASSWORD", long = "irc_nickserv_password")]
+    irc_nickserv_password: Option<String>,
+
     #[clap(env = "BRIDGE_DISCORD_TOKEN")]
     discord_token: String,
 
@@ -48,9 +232,131 @@ pub struct Config {
     #[clap(env = "BRIDGE_DISCORD_CHANNEL")]
     discord_channel: u64,
 
+    /// Guild to register slash commands in, defaults to the guild of the webhook
+    #[clap(env = "BRIDGE_DISCORD_GUILD", long = "discord_guild")]
+    discord_guild: Option<u64>,
+
+    /// Register slash commands for every guild the bot is in instead of a single one
+    #[clap(
+        env = "BRIDGE_DISCORD_GLOBAL_COMMANDS",
+        long = "discord_global_commands",
+        action = ArgAction::Set,
+        default_value_t = false
+    )]
+    discord_global_commands: bool,
+
+    /// Database to store linked users in, a sqlite path or URL or a postgres:// URL
     #[clap(env = "BRIDGE_SQLITE_PATH")]
     sqlite_path: String,
 
+    /// Apply the database migrations at startup, disable when they are managed separately
+    #[clap(
+        env = "BRIDGE_RUN_MIGRATIONS",
+        long = "run_migrations",
+        action = ArgAction::Set,
+        default_value_t = true
+    )]
+    run_migrations: bool,
+
+    /// How Discord users are shown at the start of their messages on irc, such as "<{nick}>" or
+    /// "[d] {nick}:"
+    #[clap(
+        env = "BRIDGE_IRC_NICK_TEMPLATE",
+        long = "irc_nick_template",
+        value_parser = irc_format::parse_nick_template,
+        default_value = "<{nick}>"
+    )]
+    irc_nick_template: String,
+
+    /// Put a zero-width space in relayed nicks, so irc users with the same nick are not
+    /// highlighted
+    #[clap(
+        env = "BRIDGE_IRC_ANTI_HIGHLIGHT",
+        long = "irc_anti_highlight",
+        action = ArgAction::Set,
+        default_value_t = false
+    )]
+    irc_anti_highlight: bool,
+
+    /// Colour relayed nicks on irc, each Discord user always in the same colour
+    #[clap(
+        env = "BRIDGE_IRC_NICK_COLOURS",
+        long = "irc_nick_colours",
+        action = ArgAction::Set,
+        default_value_t = false
+    )]
+    irc_nick_colours: bool,
+
+    /// Hide most of an irc user's host in /whois answers on discord
+    #[clap(
+        env = "BRIDGE_MASK_WHOIS_HOSTS",
+        long = "mask_whois_hosts",
+        action = ArgAction::Set,
+        default_value_t = true
+    )]
+    mask_whois_hosts: bool,
+
+    /// Discord users allowed to use admin commands, who are also sent messages flagged by the
+    /// filter rules
+    #[clap(
+        env = "BRIDGE_ADMIN_DISCORD_USERS",
+        long = "admin_discord_user",
+        value_delimiter = ','
+    )]
+    admin_discord_users: Vec<u64>,
+
+    /// irc services accounts allowed to use admin commands
+    #[clap(
+        env = "BRIDGE_ADMIN_IRC_ACCOUNTS",
+        long = "admin_irc_account",
+        value_delimiter = ','
+    )]
+    admin_irc_accounts: Vec<String>,
+
+    /// Discord roles whose members may use admin commands
+    #[clap(
+        env = "BRIDGE_ADMIN_DISCORD_ROLES",
+        long = "admin_discord_role",
+        value_delimiter = ','
+    )]
+    admin_discord_roles: Vec<u64>,
+
+    /// irc nick!user@host globs allowed to use admin commands
+    #[clap(
+        env = "BRIDGE_ADMIN_IRC_HOSTMASKS",
+        long = "admin_irc_hostmask",
+        value_delimiter = ','
+    )]
+    admin_irc_hostmasks: Vec<String>,
+
+    /// Added to the names irc users get on Discord, such as " (IRC)"
+    #[clap(
+        env = "BRIDGE_WEBHOOK_NAME_SUFFIX",
+        long = "webhook_name_suffix",
+        default_value = ""
+    )]
+    webhook_name_suffix: String,
+
+    /// TOML file of regex rules that drop, rewrite, mask or flag relayed messages
+    #[clap(env = "BRIDGE_FILTER_RULES", long = "filter_rules")]
+    filter_rules: Option<String>,
+
+    /// Nicks of other relay bots in the irc channel, whose messages are never bridged
+    #[clap(
+        env = "BRIDGE_IRC_RELAY_BOTS",
+        long = "irc_relay_bot",
+        value_delimiter = ','
+    )]
+    irc_relay_bots: Vec<String>,
+
+    /// User IDs of other relay bots in the discord channel, whose messages are never bridged
+    #[clap(
+        env = "BRIDGE_DISCORD_RELAY_BOTS",
+        long = "discord_relay_bot",
+        value_delimiter = ','
+    )]
+    discord_relay_bots: Vec<u64>,
+
     #[clap(env = "IRC_IGNORED_USERS", long = "irc_ignored")]
     ignored_irc_users: Vec<String>,
 
@@ -58,38 +364,71 @@ pub struct Config {
     ignored_discord_users: Vec<u64>,
 }
 
+impl Config {
+    fn irc_username(&self) -> &str {
+        self.irc_username.as_deref().unwrap_or(&self.irc_nick)
+    }
+
+    fn irc_realname(&self) -> &str {
+        self.irc_realname.as_deref().unwrap_or(&self.irc_nick)
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let config = Config::parse();
+    irc_connection::validate_config(&config)?;
+    irc_login::validate_config(&config)?;
 
     println!("LOG: READ CONFIG");
 
+    println!("LOG: Connecting to irc");
+
+    let relay = irc_connection::connect(&config).await?;
+
+    // Channels are joined by irc_login once we are authenticated, so they are left out here to
+    // stop the irc client from joining them as soon as the MOTD ends
     let irc_config = irc::client::prelude::Config {
         nickname: Some(config.irc_nick.clone()),
-        server: Some(config.irc_host.clone()),
-        port: Some(
-            str::parse(&config.irc_port.clone()).expect("Must be an integer between 0 and 65536"),
-        ),
-        channels: vec![config.irc_channel.clone()],
-        use_tls: Some(true),
+        // Alternate nicks are left to the NickTracker, see there for why
+        alt_nicks: vec![],
+        username: Some(config.irc_username().to_string()),
+        realname: Some(config.irc_realname().to_string()),
+        server: Some(relay.address.ip().to_string()),
+        port: Some(relay.address.port()),
+        ping_time: Some(config.irc_ping_interval),
+        ping_timeout: Some(config.irc_ping_timeout),
         ..Default::default()
     };
 
-    println!("LOG: Connecting to irc");
-
     let mut client = irc::client::Client::from_config(irc_config)
         .await
         .expect("Cannot connect to irc");
-    println!("LOG: Identifying to irc server");
-    client.identify()?;
 
-    let pool = SqlitePool::connect(&config.sqlite_path).await?;
+    let sender = client.sender();
+    relay.authenticate(&sender)?;
+    let mut stream = client.stream().expect("Cannot get stream");
+    let nick = NickTracker::new(&config, sender.clone());
+    let caps = Capabilities::new(sender.clone());
+    let delivery = DeliveryTracker::new(caps.clone(), sender.clone());
+    let accounts = AccountTracker::new(sender.clone());
+    let pending_links = PendingLinks::default();
+    let names = NamesCollector::default();
+    let whois = WhoisCollector::default();
+
+    println!("LOG: Logging in to irc server");
+    irc_login::login(&config, &sender, &mut stream, &nick, &caps, &accounts).await?;
+
+    let users = user_store::connect(&config.sqlite_path, config.run_migrations).await?;
+    let ignores = IgnoreList::load(users.clone(), &config).await?;
+    let direction = PairDirection::load(users.clone(), &config).await?;
+    let opt_outs = OptOuts::load(users.clone()).await?;
+    let mentions = PairMentions::load(users.clone(), &config).await?;
+    let echoes = EchoFilter::default();
+    let filters = Filters::load(&config).await?;
 
     println!("LOG: Connected to irc");
 
-    let sender = client.sender();
-    let stream = client.stream().expect("Cannot get stream");
-
     let clientref = Arc::new(Mutex::new(client));
 
     let http = Http::new_with_application_id(&config.discord_token, config.application_id);
@@ -98,25 +437,49 @@ async fn main() -> Result<()> {
 
     let (irc_command_sender, irc_command_receiver) = channel(20);
     let (discord_command_sender, discord_command_receiver) = channel(20);
-    let (irc_response_callback_sender, irc_response_callback_receiver) = channel(20);
     let senders = BridgeSenders {
         irc: irc_command_sender.clone(),
         discord: discord_command_sender.clone(),
-        irc_callback: irc_response_callback_sender.clone(),
     };
 
     let handler = discord::Handler {
         config: config.clone(),
         irc_sender: sender.clone(),
         client_ref: clientref.clone(),
-        ignored_users: vec![1021460721239867535.into()],
+        ignores: ignores.clone(),
+        direction: direction.clone(),
+        opt_outs: opt_outs.clone(),
+        mentions: mentions.clone(),
+        echoes: echoes.clone(),
+        filters: filters.clone(),
         webhook_id: webhook.id,
-        database_pool: pool.clone(),
+        users: users.clone(),
         senders: senders.clone(),
+        pending_links: pending_links.clone(),
+        nick: nick.clone(),
     };
 
     println!("LOG: Created discord handler");
 
+    let irc_context = irc_side::IrcContext {
+        users: users.clone(),
+        config: config.clone(),
+        senders: senders.clone(),
+        names: names.clone(),
+        whois: whois.clone(),
+        nick_tracker: nick.clone(),
+        caps: caps.clone(),
+        delivery: delivery.clone(),
+        accounts: accounts.clone(),
+        pending_links: pending_links.clone(),
+        ignores: ignores.clone(),
+        direction: direction.clone(),
+        opt_outs: opt_outs.clone(),
+        echoes: echoes.clone(),
+        mentions: mentions.clone(),
+        filters: filters.clone(),
+    };
+
     let framework = StandardFramework::new().configure(|c| c.prefix("~"));
 
     // Login with a bot token from the environment
@@ -128,25 +491,66 @@ async fn main() -> Result<()> {
         .await
         .expect("Error creating client");
 
-    register_discord_slash_commands(config.clone()).await?;
+    register_discord_slash_commands(&config, &http, webhook.guild_id).await?;
 
     let _ = select! {
-        Ok(()) = discord_sender(config.clone(), discord_command_receiver) => {},
+        Ok(()) = discord_sender(
+            config.clone(),
+            discord_command_receiver,
+            echoes.clone(),
+            mentions.clone(),
+        ) => {},
         Ok(()) = discord::discord_receiver(discord_client) => {},
-        Ok(()) = irc_side::irc_receiver(stream, pool.clone(), config.clone(), senders.clone(), irc_response_callback_receiver) => {}
-        Ok(()) = irc_sender(config.clone(), sender.clone(), irc_command_receiver, senders.clone()) => {},
+        Ok(()) = irc_side::irc_receiver(stream, irc_context) => {}
+        Ok(()) = irc_sender(
+            config.clone(),
+            sender.clone(),
+            irc_command_receiver,
+            names.clone(),
+            whois.clone(),
+            delivery.clone(),
+            echoes.clone(),
+        ) => {},
+        Ok(()) = irc_nick::reclaim_nick(config.clone(), nick.clone()) => {},
     };
 
     Ok(())
 }
 
-async fn register_discord_slash_commands(config: Config) -> Result<()> {
-    let http = Http::new_with_application_id(&config.discord_token, config.application_id);
+/// Registers the slash commands, either for one guild or globally. The whole set is overwritten
+/// at once, so commands that no longer exist are removed, and the commands are cleared from the
+/// other scope so they do not show up twice.
+async fn register_discord_slash_commands(
+    config: &Config,
+    http: &Http,
+    webhook_guild: Option<GuildId>,
+) -> Result<()> {
+    let guild = config.discord_guild.map(GuildId).or(webhook_guild);
+
+    if config.discord_global_commands {
+        println!("LOG: Registering slash commands globally");
+        ApplicationCommand::set_global_application_commands(http, slash_commands).await?;
+        if let Some(guild) = guild {
+            guild
+                .set_application_commands(http, |commands| commands)
+                .await?;
+        }
+    } else {
+        let guild = guild.ok_or(
+            "No discord guild to register commands in, set BRIDGE_DISCORD_GUILD or register them \
+             globally",
+        )?;
+        println!("LOG: Registering slash commands in guild {guild}");
+        guild.set_application_commands(http, slash_commands).await?;
+        ApplicationCommand::set_global_application_commands(http, |commands| commands).await?;
+    }
 
-    let guild = http.get_guild(541017705356984330).await?;
+    Ok(())
+}
 
-    guild
-        .create_application_command(&http, |command| {
+fn slash_commands(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
             command
                 .name("connect_user")
                 .description("Connect your discord username to a irc nick")
@@ -154,21 +558,205 @@ async fn register_discord_slash_commands(config: Config) -> Result<()> {
                     option
                         .name("nick")
                         .description("the nick to use")
-                        .kind(serenity::model::prelude::command::CommandOptionType::String)
+                        .kind(CommandOptionType::String)
                         .required(true)
                 })
         })
-        .await?;
-
-    guild
-        .create_application_command(&http, |command| {
+        .create_application_command(|command| {
             command
                 .name("users")
                 .description("Show the currently logged in users in the irc channel")
         })
-        .await?;
+        .create_application_command(|command| {
+            command
+                .name("unlink")
+                .description("Disconnect your discord account from its irc nick")
+        })
+        .create_application_command(|command| {
+            command
+                .name("optout")
+                .description("Stop your messages from being bridged to irc")
+        })
+        .create_application_command(|command| {
+            command
+                .name("optin")
+                .description("Have your messages bridged to irc again")
+        })
+        .create_application_command(|command| {
+            command
+                .name("whois")
+                .description("Show who an irc user is and which discord user they are linked to")
+                .create_option(|option| {
+                    option
+                        .name("target")
+                        .description("an irc nick, or a discord user as @name")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("msg")
+                .description("Send a private message to someone on irc")
+                .create_option(|option| {
+                    option
+                        .name("nick")
+                        .description("irc nick to message")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("text")
+                        .description("message to send")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("bridge")
+                .description("Manage the bridge (admins only)")
+                .create_option(|group| {
+                    group
+                        .name("ignore")
+                        .description("Stop bridging messages from someone")
+                        .kind(CommandOptionType::SubCommandGroup)
+                        .create_sub_option(|add| {
+                            ignore_options(add.name("add").description("Ignore someone"))
+                        })
+                        .create_sub_option(|remove| {
+                            ignore_options(
+                                remove.name("remove").description("Stop ignoring someone"),
+                            )
+                        })
+                        .create_sub_option(|list| {
+                            list.name("list")
+                                .description("List everyone who is ignored")
+                                .kind(CommandOptionType::SubCommand)
+                        })
+                })
+                .create_option(|pause| {
+                    pause
+                        .name("pause")
+                        .description("Pause bridging, or only bridge one way")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(|option| {
+                            option
+                                .name("direction")
+                                .description("the way messages still go, none by default")
+                                .kind(CommandOptionType::String);
+                            for direction in [Direction::IrcToDiscord, Direction::DiscordToIrc] {
+                                option.add_string_choice(direction.name(), direction.name());
+                            }
+                            option
+                        })
+                })
+                .create_option(|resume| {
+                    resume
+                        .name("resume")
+                        .description("Bridge messages both ways again")
+                        .kind(CommandOptionType::SubCommand)
+                })
+                .create_option(|mentions| {
+                    mentions
+                        .name("mentions")
+                        .description("Choose whether messages from irc can ping everyone and roles")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(|option| {
+                            option
+                                .name("policy")
+                                .description("allow or deny pings of everyone, here and roles")
+                                .kind(CommandOptionType::String)
+                                .required(true);
+                            for policy in [MentionPolicy::Deny, MentionPolicy::Allow] {
+                                option.add_string_choice(policy.name(), policy.name());
+                            }
+                            option
+                        })
+                })
+                .create_option(|reload| {
+                    reload
+                        .name("reload")
+                        .description("Reload the ignores and the filter rules")
+                        .kind(CommandOptionType::SubCommand)
+                })
+                .create_option(|link| {
+                    link.name("link")
+                        .description("Link an irc nick to a discord user without a code")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(|option| {
+                            option
+                                .name("nick")
+                                .description("the irc nick")
+                                .kind(CommandOptionType::String)
+                                .required(true)
+                        })
+                        .create_sub_option(|option| {
+                            option
+                                .name("user")
+                                .description("the discord user")
+                                .kind(CommandOptionType::User)
+                                .required(true)
+                        })
+                        .create_sub_option(|option| {
+                            option
+                                .name("account")
+                                .description("services account to verify the link for")
+                                .kind(CommandOptionType::String)
+                        })
+                })
+                .create_option(|avatar| {
+                    avatar
+                        .name("avatar")
+                        .description("Set or reset the avatar of an irc nick")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(|option| {
+                            option
+                                .name("nick")
+                                .description("the irc nick")
+                                .kind(CommandOptionType::String)
+                                .required(true)
+                        })
+                        .create_sub_option(|option| {
+                            option
+                                .name("url")
+                                .description("image url, leave out to reset")
+                                .kind(CommandOptionType::String)
+                        })
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("links")
+                .description("List every linked irc nick (admins only)")
+        })
+}
 
-    Ok(())
+/// The kind and value options of the ignore add and remove subcommands
+fn ignore_options(
+    subcommand: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    subcommand
+        .kind(CommandOptionType::SubCommand)
+        .create_sub_option(|option| {
+            option
+                .name("kind")
+                .description("what the value is")
+                .kind(CommandOptionType::String)
+                .required(true);
+            for kind in ignores::IgnoreKind::ALL {
+                option.add_string_choice(kind.name(), kind.name());
+            }
+            option
+        })
+        .create_sub_option(|option| {
+            option
+                .name("value")
+                .description("a discord user, irc nick, nick!user@host glob or irc account")
+                .kind(CommandOptionType::String)
+                .required(true)
+        })
 }
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
@@ -177,7 +765,6 @@ pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 pub struct BridgeSenders {
     irc: tokio::sync::mpsc::Sender<IrcRequest>,
     discord: tokio::sync::mpsc::Sender<DiscordRequest>,
-    irc_callback: tokio::sync::mpsc::Sender<IrcResponseCallback>,
 }
 
 #[derive(Debug)]
@@ -186,28 +773,64 @@ pub enum IrcRequest {
         to: String,
         message: String,
     },
+    /// Asks for the names in the bridged channel, which are sent back on `reply`
     Names {
-        interaction: ApplicationCommandInteraction,
+        reply: oneshot::Sender<Vec<String>>,
+    },
+    /// Looks a nick up with WHOIS, sending back None when it is not on the server
+    Whois {
+        nick: String,
+        reply: oneshot::Sender<Option<Whois>>,
     },
 }
 
 #[derive(Debug)]
 pub enum DiscordRequest {
-    SendMessage { alias: String, message: String },
-    SetAvatar { avatar_url: Option<String> },
+    /// `mentions` are the users the bridge itself resolved, the only ones the message may ping
+    SendMessage {
+        alias: String,
+        message: String,
+        mentions: Vec<u64>,
+    },
+    SetAvatar {
+        avatar_url: Option<String>,
+    },
 }
 
-async fn discord_sender(config: Config, mut commands: Receiver<DiscordRequest>) -> Result<()> {
+async fn discord_sender(
+    config: Config,
+    mut commands: Receiver<DiscordRequest>,
+    echoes: EchoFilter,
+    pair_mentions: PairMentions,
+) -> Result<()> {
     let http = Http::new(&config.discord_token);
 
     let mut webhook = http.get_webhook_from_url(&config.discord_webhook).await?;
 
     while let Some(command) = commands.recv().await {
         match command {
-            DiscordRequest::SendMessage { alias, message } => {
+            DiscordRequest::SendMessage {
+                alias,
+                message,
+                mentions,
+            } => {
+                echoes.sent_to_discord(&message);
+                let allow_all = pair_mentions.allowed();
                 webhook
                     .execute(&http, false, |webhook| {
-                        webhook.content(message).username(alias)
+                        webhook
+                            .content(message)
+                            .username(alias)
+                            .allowed_mentions(|allowed| {
+                                if allow_all {
+                                    allowed
+                                        .parse(ParseValue::Everyone)
+                                        .parse(ParseValue::Roles)
+                                        .parse(ParseValue::Users)
+                                } else {
+                                    allowed.empty_parse().users(mentions)
+                                }
+                            })
                     })
                     .await?;
             }
@@ -224,26 +847,34 @@ async fn irc_sender(
     config: Config,
     sender: Sender,
     mut commands: Receiver<IrcRequest>,
-    senders: BridgeSenders,
+    names: NamesCollector,
+    whois: WhoisCollector,
+    delivery: DeliveryTracker,
+    echoes: EchoFilter,
 ) -> Result<()> {
     while let Some(command) = commands.recv().await {
         match command {
-            IrcRequest::SendMessage { to, message } => sender.send_privmsg(to, message)?,
-            IrcRequest::Names { interaction } => {
-                println!("Got request to get names from irc");
-                senders
-                    .irc_callback
-                    .send(IrcResponseCallback { interaction })
-                    .await?;
-                println!("Setup callback");
-                let message = Message {
-                    tags: None,
-                    prefix: None,
-                    command: Command::NAMES(Some(config.irc_channel.clone()), None),
-                };
-                println!("sending a command '{}' to irc", message);
-                sender.send(message)?;
-                println!("sent names command to irc");
+            IrcRequest::SendMessage { to, message } => {
+                if !irc_sanitise::is_valid_target(&to) {
+                    println!("LOG: Not sending a message to invalid irc target {to:?}");
+                    continue;
+                }
+                for line in irc_sanitise::message_lines(&to, &message) {
+                    if nick_eq(&to, &config.irc_channel) {
+                        echoes.sent_to_irc(&line);
+                    }
+                    delivery.send_privmsg(to.clone(), line)?;
+                }
+            }
+            IrcRequest::Names { reply } => {
+                names.expect(&config.irc_channel, reply);
+                sender.send(Command::NAMES(Some(config.irc_channel.clone()), None))?;
+            }
+            IrcRequest::Whois { nick, reply } => {
+                whois.expect(&nick, reply);
+                // Naming the nick as the target server too asks their own server, which also
+                // sends their idle time
+                sender.send(Command::WHOIS(Some(nick.clone()), nick))?;
             }
         }
     }
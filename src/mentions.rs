@@ -0,0 +1,186 @@
+//! Pings in messages relayed from irc. Anyone on irc could type @everyone, so by default the only
+//! pings that go through are of linked users the bridge found for `@nick` or a leading `nick:`.
+//! Admins can allow all pings for the channel pair.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+
+use crate::irc_nick::fold_nick;
+use crate::user_store::{User, UserStore};
+use crate::{Config, Result};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MentionPolicy {
+    /// Only ping linked users the bridge resolved
+    Deny,
+    /// Let messages from irc ping everyone, here and roles too
+    Allow,
+}
+
+impl MentionPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MentionPolicy::Deny => "deny",
+            MentionPolicy::Allow => "allow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [MentionPolicy::Deny, MentionPolicy::Allow]
+            .into_iter()
+            .find(|policy| policy.name() == name)
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            MentionPolicy::Deny => "Messages from irc only ping linked users",
+            MentionPolicy::Allow => "Messages from irc can ping everyone, here and roles",
+        }
+    }
+}
+
+/// Whether admins allowed all pings for the channel pair in the config
+#[derive(Clone)]
+pub struct PairMentions {
+    store: Arc<dyn UserStore>,
+    irc_channel: String,
+    discord_channel: u64,
+    allowed: Arc<AtomicBool>,
+}
+
+impl PairMentions {
+    pub async fn load(store: Arc<dyn UserStore>, config: &Config) -> Result<Self> {
+        let irc_channel = fold_nick(&config.irc_channel);
+        let allowed = store
+            .pair_allows_mentions(&irc_channel, config.discord_channel)
+            .await?;
+        if allowed {
+            println!("LOG: {}", MentionPolicy::Allow.describe());
+        }
+
+        Ok(Self {
+            store,
+            irc_channel,
+            discord_channel: config.discord_channel,
+            allowed: Arc::new(AtomicBool::new(allowed)),
+        })
+    }
+
+    pub fn allowed(&self) -> bool {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    pub async fn set(&self, policy: MentionPolicy) -> Result<()> {
+        let allow = policy == MentionPolicy::Allow;
+        self.store
+            .set_pair_allows_mentions(&self.irc_channel, self.discord_channel, allow)
+            .await?;
+        self.allowed.store(allow, Ordering::Relaxed);
+        println!("LOG: {}", policy.describe());
+        Ok(())
+    }
+}
+
+/// The Discord user an irc nick or Discord name is linked to. Only verified links count, anyone
+/// can ask for a link to any nick.
+async fn find_linked_id(users: &dyn UserStore, name: &str) -> Result<Option<u64>> {
+    let verified_id = |user: User| user.discord_id.filter(|_| user.verified);
+    if let Some(id) = users.find_by_nick(name).await?.and_then(verified_id) {
+        return Ok(Some(id));
+    }
+    Ok(users
+        .find_by_discord_name(name)
+        .await?
+        .and_then(verified_id))
+}
+
+/// Turns `@name` and a leading `name:` into Discord mentions where the name belongs to a linked
+/// user, returning the new text and the users it mentions
+pub async fn resolve_mentions(users: &dyn UserStore, text: &str) -> Result<(String, Vec<u64>)> {
+    let at_name = Regex::new(r"(^|\s)@([^\s@:,.!?<>]+)").expect("Could not compile regex");
+    let addressed = Regex::new(r"^([^\s@:,<>]+)([:,])(\s|$)").expect("Could not compile regex");
+
+    let names = at_name
+        .captures_iter(text)
+        .map(|captures| captures[2].to_string())
+        .chain(
+            addressed
+                .captures(text)
+                .map(|captures| captures[1].to_string()),
+        );
+    let mut found = HashMap::new();
+    for name in names {
+        if !found.contains_key(&name)
+            && let Some(id) = find_linked_id(users, &name).await?
+        {
+            found.insert(name, id);
+        }
+    }
+    if found.is_empty() {
+        return Ok((text.to_string(), vec![]));
+    }
+
+    let text = addressed.replace(text, |captures: &Captures| match found.get(&captures[1]) {
+        Some(id) => format!("<@{id}>{}{}", &captures[2], &captures[3]),
+        None => captures[0].to_string(),
+    });
+    let text = at_name.replace_all(&text, |captures: &Captures| match found.get(&captures[2]) {
+        Some(id) => format!("{}<@{id}>", &captures[1]),
+        None => captures[0].to_string(),
+    });
+
+    let mut mentions = found.into_values().collect::<Vec<_>>();
+    mentions.sort_unstable();
+    mentions.dedup();
+    Ok((text.into_owned(), mentions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn users() -> Arc<dyn UserStore> {
+        let users = crate::user_store::in_memory().await.unwrap();
+        users
+            .upsert_link("alice", 1234, "alice_d", "Alice")
+            .await
+            .unwrap();
+        users.verify("alice", 1234, "alice").await.unwrap();
+        users
+            .upsert_link("mallory", 5678, "boss", "Boss")
+            .await
+            .unwrap();
+        users
+    }
+
+    #[tokio::test]
+    async fn verified_users_are_pinged() {
+        let users = users().await;
+        assert_eq!(
+            resolve_mentions(users.as_ref(), "alice: hi @ALICE, @alice_d?")
+                .await
+                .unwrap(),
+            ("<@1234>: hi <@1234>, <@1234>?".to_string(), vec![1234])
+        );
+    }
+
+    #[tokio::test]
+    async fn unverified_and_unknown_names_are_left_alone() {
+        let users = users().await;
+        for text in [
+            "mallory: hi",
+            "hi @boss",
+            "hi @nobody",
+            "email@alice.example",
+        ] {
+            assert_eq!(
+                resolve_mentions(users.as_ref(), text).await.unwrap(),
+                (text.to_string(), vec![])
+            );
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! People who asked for their messages not to be bridged. Opt-outs are stored like ignores, by
+//! Discord ID, irc account or irc nick, but are managed by the people themselves, who are told
+//! once that their messages are not being relayed.
+
+use std::sync::{Arc, RwLock};
+
+use crate::ignores::{Ignore, IgnoreKind};
+use crate::user_store::UserStore;
+use crate::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptOutStatus {
+    Bridged,
+    /// Not bridged, `notify` is set the first time so they can be told why
+    OptedOut {
+        notify: bool,
+    },
+}
+
+#[derive(Clone)]
+pub struct OptOuts {
+    store: Arc<dyn UserStore>,
+    /// Every opt-out, with whether its owner was told about it
+    opt_outs: Arc<RwLock<Vec<(Ignore, bool)>>>,
+}
+
+impl OptOuts {
+    pub async fn load(store: Arc<dyn UserStore>) -> Result<Self> {
+        let opt_outs = store.all_opt_outs().await?;
+        Ok(Self {
+            store,
+            opt_outs: Arc::new(RwLock::new(opt_outs)),
+        })
+    }
+
+    /// An irc user is known by their account when logged in, since their nick may change
+    fn irc_key(nick: &str, account: Option<&str>) -> Result<Ignore> {
+        Ok(match account {
+            Some(account) => Ignore::new(IgnoreKind::Account, account)?,
+            None => Ignore::new(IgnoreKind::Nick, nick)?,
+        })
+    }
+
+    /// Both the nick and the account, whichever they opted out with
+    fn irc_keys(nick: &str, account: Option<&str>) -> Result<Vec<Ignore>> {
+        let mut keys = vec![Ignore::new(IgnoreKind::Nick, nick)?];
+        if let Some(account) = account {
+            keys.push(Ignore::new(IgnoreKind::Account, account)?);
+        }
+        Ok(keys)
+    }
+
+    fn discord_key(discord_id: u64) -> Result<Ignore> {
+        Ok(Ignore::new(IgnoreKind::Discord, &discord_id.to_string())?)
+    }
+
+    /// Returns false if they had already opted out
+    async fn opt_out(&self, key: Ignore) -> Result<bool> {
+        if !self.store.add_opt_out(&key).await? {
+            return Ok(false);
+        }
+        println!("LOG: {} opted out of bridging", key.describe());
+        self.opt_outs
+            .write()
+            .expect("opt out lock poisoned")
+            .push((key, false));
+        Ok(true)
+    }
+
+    /// Returns false if they had not opted out
+    async fn opt_in(&self, keys: &[Ignore]) -> Result<bool> {
+        let mut removed = false;
+        for key in keys {
+            if self.store.remove_opt_out(key).await? {
+                println!("LOG: {} opted in to bridging", key.describe());
+                removed = true;
+            }
+        }
+        self.opt_outs
+            .write()
+            .expect("opt out lock poisoned")
+            .retain(|(existing, _)| !keys.contains(existing));
+        Ok(removed)
+    }
+
+    pub async fn opt_out_discord(&self, discord_id: u64) -> Result<bool> {
+        let key = Self::discord_key(discord_id)?;
+        self.opt_out(key).await
+    }
+
+    pub async fn opt_in_discord(&self, discord_id: u64) -> Result<bool> {
+        let key = Self::discord_key(discord_id)?;
+        self.opt_in(&[key]).await
+    }
+
+    pub async fn opt_out_irc(&self, nick: &str, account: Option<&str>) -> Result<bool> {
+        let key = Self::irc_key(nick, account)?;
+        self.opt_out(key).await
+    }
+
+    /// Opts in both the account and the nick, whichever they opted out with
+    pub async fn opt_in_irc(&self, nick: &str, account: Option<&str>) -> Result<bool> {
+        self.opt_in(&Self::irc_keys(nick, account)?).await
+    }
+
+    async fn status(&self, keys: &[Ignore]) -> Result<OptOutStatus> {
+        let key = {
+            let mut opt_outs = self.opt_outs.write().expect("opt out lock poisoned");
+            let Some((key, notified)) = opt_outs.iter_mut().find(|(key, _)| keys.contains(key))
+            else {
+                return Ok(OptOutStatus::Bridged);
+            };
+            if *notified {
+                return Ok(OptOutStatus::OptedOut { notify: false });
+            }
+            *notified = true;
+            key.clone()
+        };
+
+        self.store.mark_opt_out_notified(&key).await?;
+        Ok(OptOutStatus::OptedOut { notify: true })
+    }
+
+    /// Whether any of the keys opted out, without counting as telling them
+    fn opted_out(&self, keys: &[Ignore]) -> bool {
+        self.opt_outs
+            .read()
+            .expect("opt out lock poisoned")
+            .iter()
+            .any(|(key, _)| keys.contains(key))
+    }
+
+    pub fn discord_opted_out(&self, discord_id: u64) -> Result<bool> {
+        Ok(self.opted_out(&[Self::discord_key(discord_id)?]))
+    }
+
+    pub fn irc_opted_out(&self, nick: &str, account: Option<&str>) -> Result<bool> {
+        Ok(self.opted_out(&Self::irc_keys(nick, account)?))
+    }
+
+    pub async fn discord_status(&self, discord_id: u64) -> Result<OptOutStatus> {
+        let key = Self::discord_key(discord_id)?;
+        self.status(&[key]).await
+    }
+
+    pub async fn irc_status(&self, nick: &str, account: Option<&str>) -> Result<OptOutStatus> {
+        self.status(&Self::irc_keys(nick, account)?).await
+    }
+}
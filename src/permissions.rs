@@ -0,0 +1,47 @@
+//! Who may use the admin commands, and the audit log of every time someone tries to
+
+use serenity::model::prelude::{Member, UserId};
+
+use crate::ignores::glob_match;
+use crate::irc_nick::fold_nick;
+use crate::user_store::UserStore;
+use crate::Config;
+
+/// Admins on Discord are listed by user ID or hold one of the admin roles
+pub fn is_discord_admin(config: &Config, user: UserId, member: Option<&Member>) -> bool {
+    config.admin_discord_users.contains(&user.0)
+        || member.is_some_and(|member| {
+            member
+                .roles
+                .iter()
+                .any(|role| config.admin_discord_roles.contains(&role.0))
+        })
+}
+
+/// Admins on irc are logged in to an admin account or match an admin hostmask
+pub fn is_irc_admin(config: &Config, hostmask: Option<&str>, account: Option<&str>) -> bool {
+    let account = account.map(fold_nick);
+    let hostmask = hostmask.map(fold_nick);
+
+    config
+        .admin_irc_accounts
+        .iter()
+        .any(|admin| account.as_ref() == Some(&fold_nick(admin)))
+        || hostmask.is_some_and(|hostmask| {
+            config
+                .admin_irc_hostmasks
+                .iter()
+                .any(|admin| glob_match(&fold_nick(admin), &hostmask))
+        })
+}
+
+/// Records a use of an admin command. Failing to write the audit log is only logged, so a
+/// database problem does not lock admins out.
+pub async fn audit(users: &dyn UserStore, side: &str, actor: &str, action: &str, allowed: bool) {
+    let verdict = if allowed { "allowed" } else { "denied" };
+    println!("LOG: Admin command on {side} by {actor}, {verdict}: {action}");
+
+    if let Err(e) = users.record_audit(side, actor, action, allowed).await {
+        println!("LOG: Could not write audit log: {e}");
+    }
+}
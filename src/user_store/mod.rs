@@ -0,0 +1,1061 @@
+//! Storage for linked users, private message routes, ignores, opt-outs, bridge settings and the
+//! audit log. Each database has its own implementation of [`UserStore`], picked by the scheme of
+//! the database URL, with its own set of migrations. Nicks and accounts are looked up by their
+//! [`fold_nick`] form, which the stores keep next to the nick as it was written.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::direction::Direction;
+use crate::ignores::{Ignore, IgnoreKind};
+use crate::irc_nick::fold_nick;
+use crate::Result;
+
+mod postgres;
+mod sqlite;
+
+/// A row of the users table: an irc nick and the Discord user it is linked to, if any
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub irc_nick: String,
+    pub irc_account: Option<String>,
+    pub discord_id: Option<u64>,
+    pub discord_nick: Option<String>,
+    pub discord_name: Option<String>,
+    pub verified: bool,
+    pub avatar: Option<String>,
+}
+
+const SELECT_USERS: &str = "SELECT ircnick AS irc_nick, ircaccount AS irc_account,
+    discordid AS discord_id, discordnick AS discord_nick, discordname AS discord_name,
+    verified, avatar
+FROM users";
+
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    irc_nick: String,
+    irc_account: Option<String>,
+    discord_id: Option<i64>,
+    discord_nick: Option<String>,
+    discord_name: Option<String>,
+    verified: bool,
+    avatar: Option<String>,
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> Self {
+        User {
+            irc_nick: row.irc_nick,
+            irc_account: row.irc_account,
+            discord_id: row.discord_id.map(|id| id as u64),
+            discord_nick: row.discord_nick,
+            discord_name: row.discord_name,
+            verified: row.verified,
+            avatar: row.avatar,
+        }
+    }
+}
+
+impl User {
+    pub fn avatar_source(&self) -> &'static str {
+        match &self.avatar {
+            Some(avatar) if avatar.starts_with("https://www.gravatar.com/") => "gravatar",
+            Some(_) => "custom url",
+            None if self.verified => "discord",
+            None => "default",
+        }
+    }
+
+    /// One line summary of the link, as shown by the whois and links commands
+    pub fn describe(&self) -> String {
+        let discord = match (&self.discord_name, self.discord_id) {
+            (Some(name), Some(id)) => format!("{name} ({id})"),
+            (Some(name), None) => name.clone(),
+            (None, Some(id)) => id.to_string(),
+            (None, None) => return format!("{} is not linked to Discord", self.irc_nick),
+        };
+        let account = self
+            .irc_account
+            .as_ref()
+            .map(|account| format!(" [account {account}]"))
+            .unwrap_or_default();
+        let verified = if self.verified {
+            "verified"
+        } else {
+            "not verified"
+        };
+
+        format!(
+            "{}{account} is linked to Discord user {discord}, {verified}, avatar from {}",
+            self.irc_nick,
+            self.avatar_source()
+        )
+    }
+}
+
+/// Typed access to the users table, shared by the irc and Discord sides
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn find_by_nick(&self, irc_nick: &str) -> Result<Option<User>>;
+
+    async fn find_verified_by_account(&self, irc_account: &str) -> Result<Option<User>>;
+
+    async fn find_by_discord_id(&self, discord_id: u64) -> Result<Option<User>>;
+
+    /// Finds a link by the Discord username or server nickname
+    async fn find_by_discord_name(&self, name: &str) -> Result<Option<User>>;
+
+    /// Every irc nick that has a Discord user attached
+    async fn all_linked(&self) -> Result<Vec<User>>;
+
+    /// Attaches a Discord user to an irc nick, unverified until the irc side confirms it. The
+    /// Discord user is detached from any other nick. Returns false without changing anything if
+    /// the nick is already verified as someone else's.
+    async fn upsert_link(
+        &self,
+        irc_nick: &str,
+        discord_id: u64,
+        discord_nick: &str,
+        discord_name: &str,
+    ) -> Result<bool>;
+
+    /// Marks the link between the irc nick and the Discord user as verified for the services
+    /// account, detaching the Discord user from any other nick
+    async fn verify(&self, irc_nick: &str, discord_id: u64, irc_account: &str) -> Result<()>;
+
+    /// Sets or clears the custom avatar for an irc nick
+    async fn set_avatar(&self, irc_nick: &str, avatar: Option<&str>) -> Result<()>;
+
+    /// Revokes the verified link of a nick that is now logged in to a different account than the
+    /// one it was verified with. Returns whether a link was revoked.
+    async fn revoke_if_account_changed(&self, irc_nick: &str, irc_account: &str) -> Result<bool>;
+
+    /// Removes the Discord side of any link for the irc user, keeping their avatar settings.
+    /// Returns whether there was a link to remove.
+    async fn unlink_irc(&self, irc_nick: &str, irc_account: Option<&str>) -> Result<bool>;
+
+    /// Removes the link to the Discord user. Returns whether there was a link to remove.
+    async fn unlink_discord(&self, discord_id: u64) -> Result<bool>;
+
+    /// Sends private messages from the irc nick to the Discord user from now on, replacing any
+    /// other route for the nick, and marks the conversation as active
+    async fn open_private_route(&self, irc_nick: &str, discord_id: u64) -> Result<()>;
+
+    /// The Discord user that private messages from the irc nick go to, if they talked within
+    /// `max_idle`
+    async fn find_private_route_by_nick(
+        &self,
+        irc_nick: &str,
+        max_idle: Duration,
+    ) -> Result<Option<u64>>;
+
+    /// The irc nick the Discord user most recently talked to within `max_idle`
+    async fn find_private_route_by_discord_id(
+        &self,
+        discord_id: u64,
+        max_idle: Duration,
+    ) -> Result<Option<String>>;
+
+    /// Forgets routes that have been idle for longer than `max_idle`
+    async fn expire_private_routes(&self, max_idle: Duration) -> Result<()>;
+
+    /// Returns false if the ignore was already stored
+    async fn add_ignore(&self, ignore: &Ignore) -> Result<bool>;
+
+    /// Returns false if there was no such ignore
+    async fn remove_ignore(&self, ignore: &Ignore) -> Result<bool>;
+
+    async fn all_ignores(&self) -> Result<Vec<Ignore>>;
+
+    /// Returns false if they had already opted out
+    async fn add_opt_out(&self, opt_out: &Ignore) -> Result<bool>;
+
+    /// Returns false if they had not opted out
+    async fn remove_opt_out(&self, opt_out: &Ignore) -> Result<bool>;
+
+    /// Every opt-out, with whether its owner was told their messages are not bridged
+    async fn all_opt_outs(&self) -> Result<Vec<(Ignore, bool)>>;
+
+    async fn mark_opt_out_notified(&self, opt_out: &Ignore) -> Result<()>;
+
+    /// The direction stored for a channel pair, if it was ever changed
+    async fn pair_direction(
+        &self,
+        irc_channel: &str,
+        discord_channel: u64,
+    ) -> Result<Option<Direction>>;
+
+    async fn set_pair_direction(
+        &self,
+        irc_channel: &str,
+        discord_channel: u64,
+        direction: Direction,
+    ) -> Result<()>;
+
+    /// Whether admins let messages from irc ping everyone, here and roles in a channel pair
+    async fn pair_allows_mentions(&self, irc_channel: &str, discord_channel: u64) -> Result<bool>;
+
+    async fn set_pair_allows_mentions(
+        &self,
+        irc_channel: &str,
+        discord_channel: u64,
+        allow: bool,
+    ) -> Result<()>;
+
+    /// Adds a use of an admin command to the audit log
+    async fn record_audit(
+        &self,
+        side: &str,
+        actor: &str,
+        action: &str,
+        allowed: bool,
+    ) -> Result<()>;
+
+    /// Finds the stored user for someone on irc. A verified link belongs to the services account
+    /// it was made from, so it is looked up by account, and a row found by nick only counts as
+    /// verified when the nick is logged in to that same account.
+    async fn find_irc_user(
+        &self,
+        irc_nick: &str,
+        irc_account: Option<&str>,
+    ) -> Result<Option<User>> {
+        if let Some(account) = irc_account
+            && let Some(user) = self.find_verified_by_account(account).await?
+        {
+            return Ok(Some(user));
+        }
+
+        Ok(self.find_by_nick(irc_nick).await?.map(|mut user| {
+            user.verified &= irc_account.is_some()
+                && user.irc_account.as_deref().map(fold_nick) == irc_account.map(fold_nick);
+            user
+        }))
+    }
+}
+
+/// Turns rows of the ignores table back into ignores, skipping kinds this version does not know
+fn ignores_from_rows(rows: Vec<(String, String)>) -> Vec<Ignore> {
+    rows.into_iter()
+        .filter_map(|(kind, value)| {
+            Some(Ignore {
+                kind: IgnoreKind::from_name(&kind)?,
+                value,
+            })
+        })
+        .collect()
+}
+
+/// Turns rows of the optouts table back into opt-outs, like [`ignores_from_rows`]
+fn opt_outs_from_rows(rows: Vec<(String, String, bool)>) -> Vec<(Ignore, bool)> {
+    rows.into_iter()
+        .filter_map(|(kind, value, notified)| {
+            let kind = IgnoreKind::from_name(&kind)?;
+            Some((Ignore { kind, value }, notified))
+        })
+        .collect()
+}
+
+/// The unix time a route must have been active since to still count
+fn active_since(max_idle: Duration) -> i64 {
+    chrono::Utc::now().timestamp() - max_idle.as_secs() as i64
+}
+
+/// Implements [`UserStore`] for a store with a `pool` of either database. The queries are written
+/// once for both: sqlite numbers `$1` placeholders the way Postgres does, and the SQL sticks to
+/// what both understand, so nicks are compared by their folded key instead of a collation.
+macro_rules! impl_user_store {
+    ($store:ty) => {
+        impl $store {
+            async fn find_one(&self, condition: &str, value: &str) -> Result<Option<User>> {
+                Ok(
+                    sqlx::query_as::<_, UserRow>(&format!("{SELECT_USERS} WHERE {condition}"))
+                        .bind(value)
+                        .fetch_optional(&self.pool)
+                        .await?
+                        .map(User::from),
+                )
+            }
+        }
+
+        #[async_trait]
+        impl UserStore for $store {
+            async fn find_by_nick(&self, irc_nick: &str) -> Result<Option<User>> {
+                self.find_one("ircnick_key = $1", &fold_nick(irc_nick))
+                    .await
+            }
+
+            async fn find_verified_by_account(&self, irc_account: &str) -> Result<Option<User>> {
+                self.find_one("ircaccount = $1 AND verified", &fold_nick(irc_account))
+                    .await
+            }
+
+            async fn find_by_discord_id(&self, discord_id: u64) -> Result<Option<User>> {
+                Ok(
+                    sqlx::query_as::<_, UserRow>(&format!("{SELECT_USERS} WHERE discordid = $1"))
+                        .bind(discord_id as i64)
+                        .fetch_optional(&self.pool)
+                        .await?
+                        .map(User::from),
+                )
+            }
+
+            async fn find_by_discord_name(&self, name: &str) -> Result<Option<User>> {
+                self.find_one(
+                    "lower(discordnick) = lower($1) OR lower(discordname) = lower($1)",
+                    name,
+                )
+                .await
+            }
+
+            async fn all_linked(&self) -> Result<Vec<User>> {
+                Ok(sqlx::query_as::<_, UserRow>(&format!(
+                    "{SELECT_USERS} WHERE discordid IS NOT NULL ORDER BY ircnick_key"
+                ))
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .map(User::from)
+                .collect())
+            }
+
+            async fn upsert_link(
+                &self,
+                irc_nick: &str,
+                discord_id: u64,
+                discord_nick: &str,
+                discord_name: &str,
+            ) -> Result<bool> {
+                let key = fold_nick(irc_nick);
+                let mut transaction = self.pool.begin().await?;
+
+                // Release the Discord user first, discordid is unique
+                sqlx::query(
+                    "UPDATE users SET discordid = NULL, discordnick = NULL, discordname = NULL,
+                        verified = false, ircaccount = NULL
+                    WHERE discordid = $1 AND ircnick_key != $2",
+                )
+                .bind(discord_id as i64)
+                .bind(&key)
+                .execute(&mut *transaction)
+                .await?;
+
+                let linked = sqlx::query(
+                    "INSERT INTO users
+                        (ircnick_key, ircnick, discordid, discordnick, discordname, verified)
+                    VALUES ($1, $2, $3, $4, $5, false)
+                    ON CONFLICT (ircnick_key) DO UPDATE SET
+                        discordid = excluded.discordid,
+                        discordnick = excluded.discordnick,
+                        discordname = excluded.discordname
+                    WHERE NOT users.verified OR users.discordid = excluded.discordid",
+                )
+                .bind(&key)
+                .bind(irc_nick)
+                .bind(discord_id as i64)
+                .bind(discord_nick)
+                .bind(discord_name)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected()
+                    > 0;
+
+                // Dropping the transaction without committing undoes the release
+                if !linked {
+                    return Ok(false);
+                }
+
+                transaction.commit().await?;
+                Ok(true)
+            }
+
+            async fn verify(&self, irc_nick: &str, discord_id: u64, irc_account: &str) -> Result<()> {
+                let key = fold_nick(irc_nick);
+                let mut transaction = self.pool.begin().await?;
+
+                sqlx::query(
+                    "UPDATE users SET discordid = NULL, discordnick = NULL, discordname = NULL,
+                        verified = false, ircaccount = NULL
+                    WHERE discordid = $1 AND ircnick_key != $2",
+                )
+                .bind(discord_id as i64)
+                .bind(&key)
+                .execute(&mut *transaction)
+                .await?;
+
+                sqlx::query(
+                    "UPDATE users SET verified = true, ircaccount = $1, discordid = $2
+                    WHERE ircnick_key = $3",
+                )
+                .bind(fold_nick(irc_account))
+                .bind(discord_id as i64)
+                .bind(&key)
+                .execute(&mut *transaction)
+                .await?;
+
+                transaction.commit().await?;
+                Ok(())
+            }
+
+            async fn set_avatar(&self, irc_nick: &str, avatar: Option<&str>) -> Result<()> {
+                sqlx::query(
+                    "INSERT INTO users (ircnick_key, ircnick, avatar) VALUES ($1, $2, $3)
+                    ON CONFLICT (ircnick_key) DO UPDATE SET avatar = excluded.avatar",
+                )
+                .bind(fold_nick(irc_nick))
+                .bind(irc_nick)
+                .bind(avatar)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn revoke_if_account_changed(&self, irc_nick: &str, irc_account: &str) -> Result<bool> {
+                let result = sqlx::query(
+                    "UPDATE users SET verified = false
+                    WHERE ircnick_key = $1 AND verified AND (ircaccount IS NULL OR ircaccount != $2)",
+                )
+                .bind(fold_nick(irc_nick))
+                .bind(fold_nick(irc_account))
+                .execute(&self.pool)
+                .await?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn unlink_irc(&self, irc_nick: &str, irc_account: Option<&str>) -> Result<bool> {
+                let result = sqlx::query(
+                    "UPDATE users SET discordid = NULL, discordnick = NULL, discordname = NULL,
+                        verified = false, ircaccount = NULL
+                    WHERE discordid IS NOT NULL
+                        AND (ircnick_key = $1 OR (verified AND ircaccount = $2))",
+                )
+                .bind(fold_nick(irc_nick))
+                .bind(irc_account.map(fold_nick))
+                .execute(&self.pool)
+                .await?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn unlink_discord(&self, discord_id: u64) -> Result<bool> {
+                let result = sqlx::query(
+                    "UPDATE users SET discordid = NULL, discordnick = NULL, discordname = NULL,
+                        verified = false, ircaccount = NULL
+                    WHERE discordid = $1",
+                )
+                .bind(discord_id as i64)
+                .execute(&self.pool)
+                .await?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn open_private_route(&self, irc_nick: &str, discord_id: u64) -> Result<()> {
+                sqlx::query(
+                    "INSERT INTO private_routes (ircnick, discordid, last_active)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (ircnick) DO UPDATE SET
+                        discordid = excluded.discordid,
+                        last_active = excluded.last_active",
+                )
+                .bind(fold_nick(irc_nick))
+                .bind(discord_id as i64)
+                .bind(chrono::Utc::now().timestamp())
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn find_private_route_by_nick(
+                &self,
+                irc_nick: &str,
+                max_idle: Duration,
+            ) -> Result<Option<u64>> {
+                let discord_id: Option<i64> = sqlx::query_scalar(
+                    "SELECT discordid FROM private_routes WHERE ircnick = $1 AND last_active >= $2",
+                )
+                .bind(fold_nick(irc_nick))
+                .bind(active_since(max_idle))
+                .fetch_optional(&self.pool)
+                .await?;
+                Ok(discord_id.map(|id| id as u64))
+            }
+
+            async fn find_private_route_by_discord_id(
+                &self,
+                discord_id: u64,
+                max_idle: Duration,
+            ) -> Result<Option<String>> {
+                Ok(sqlx::query_scalar(
+                    "SELECT ircnick FROM private_routes WHERE discordid = $1 AND last_active >= $2
+                    ORDER BY last_active DESC LIMIT 1",
+                )
+                .bind(discord_id as i64)
+                .bind(active_since(max_idle))
+                .fetch_optional(&self.pool)
+                .await?)
+            }
+
+            async fn expire_private_routes(&self, max_idle: Duration) -> Result<()> {
+                sqlx::query("DELETE FROM private_routes WHERE last_active < $1")
+                    .bind(active_since(max_idle))
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn add_ignore(&self, ignore: &Ignore) -> Result<bool> {
+                let result = sqlx::query(
+                    "INSERT INTO ignores (kind, value) VALUES ($1, $2)
+                    ON CONFLICT (kind, value) DO NOTHING",
+                )
+                .bind(ignore.kind.name())
+                .bind(&ignore.value)
+                .execute(&self.pool)
+                .await?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn remove_ignore(&self, ignore: &Ignore) -> Result<bool> {
+                let result = sqlx::query("DELETE FROM ignores WHERE kind = $1 AND value = $2")
+                    .bind(ignore.kind.name())
+                    .bind(&ignore.value)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn all_ignores(&self) -> Result<Vec<Ignore>> {
+                let rows = sqlx::query_as("SELECT kind, value FROM ignores ORDER BY created_at")
+                    .fetch_all(&self.pool)
+                    .await?;
+                Ok(ignores_from_rows(rows))
+            }
+
+            async fn add_opt_out(&self, opt_out: &Ignore) -> Result<bool> {
+                let result = sqlx::query(
+                    "INSERT INTO optouts (kind, value) VALUES ($1, $2)
+                    ON CONFLICT (kind, value) DO NOTHING",
+                )
+                .bind(opt_out.kind.name())
+                .bind(&opt_out.value)
+                .execute(&self.pool)
+                .await?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn remove_opt_out(&self, opt_out: &Ignore) -> Result<bool> {
+                let result = sqlx::query("DELETE FROM optouts WHERE kind = $1 AND value = $2")
+                    .bind(opt_out.kind.name())
+                    .bind(&opt_out.value)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn all_opt_outs(&self) -> Result<Vec<(Ignore, bool)>> {
+                let rows = sqlx::query_as("SELECT kind, value, notified FROM optouts ORDER BY created_at")
+                    .fetch_all(&self.pool)
+                    .await?;
+                Ok(opt_outs_from_rows(rows))
+            }
+
+            async fn mark_opt_out_notified(&self, opt_out: &Ignore) -> Result<()> {
+                sqlx::query("UPDATE optouts SET notified = true WHERE kind = $1 AND value = $2")
+                    .bind(opt_out.kind.name())
+                    .bind(&opt_out.value)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn record_audit(
+                &self,
+                side: &str,
+                actor: &str,
+                action: &str,
+                allowed: bool,
+            ) -> Result<()> {
+                sqlx::query("INSERT INTO audit_log (side, actor, action, allowed) VALUES ($1, $2, $3, $4)")
+                    .bind(side)
+                    .bind(actor)
+                    .bind(action)
+                    .bind(allowed)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn pair_direction(
+                &self,
+                irc_channel: &str,
+                discord_channel: u64,
+            ) -> Result<Option<Direction>> {
+                let direction: Option<String> = sqlx::query_scalar(
+                    "SELECT direction FROM bridge_pairs
+                    WHERE ircchannel = $1 AND discordchannel = $2",
+                )
+                .bind(irc_channel)
+                .bind(discord_channel as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+                Ok(direction.and_then(|direction| Direction::from_name(&direction)))
+            }
+
+            async fn set_pair_direction(
+                &self,
+                irc_channel: &str,
+                discord_channel: u64,
+                direction: Direction,
+            ) -> Result<()> {
+                sqlx::query(
+                    "INSERT INTO bridge_pairs (ircchannel, discordchannel, direction)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (ircchannel, discordchannel) DO UPDATE SET
+                        direction = excluded.direction,
+                        updated_at = CURRENT_TIMESTAMP",
+                )
+                .bind(irc_channel)
+                .bind(discord_channel as i64)
+                .bind(direction.name())
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn pair_allows_mentions(&self, irc_channel: &str, discord_channel: u64) -> Result<bool> {
+                let allow: Option<bool> = sqlx::query_scalar(
+                    "SELECT allow_mentions FROM bridge_pairs
+                    WHERE ircchannel = $1 AND discordchannel = $2",
+                )
+                .bind(irc_channel)
+                .bind(discord_channel as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+                Ok(allow.unwrap_or(false))
+            }
+
+            async fn set_pair_allows_mentions(
+                &self,
+                irc_channel: &str,
+                discord_channel: u64,
+                allow: bool,
+            ) -> Result<()> {
+                sqlx::query(
+                    "INSERT INTO bridge_pairs (ircchannel, discordchannel, direction, allow_mentions)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (ircchannel, discordchannel) DO UPDATE SET
+                        allow_mentions = excluded.allow_mentions,
+                        updated_at = CURRENT_TIMESTAMP",
+                )
+                .bind(irc_channel)
+                .bind(discord_channel as i64)
+                .bind(Direction::default().name())
+                .bind(allow)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_user_store!(postgres::PostgresUserStore);
+impl_user_store!(sqlite::SqliteUserStore);
+
+/// Opens the user store for a database URL, using Postgres for `postgres://` and
+/// `postgresql://` URLs and sqlite for anything else
+pub async fn connect(url: &str, run_migrations: bool) -> Result<Arc<dyn UserStore>> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Arc::new(
+            postgres::PostgresUserStore::connect(url, run_migrations).await?,
+        ))
+    } else {
+        Ok(Arc::new(
+            sqlite::SqliteUserStore::connect(url, run_migrations).await?,
+        ))
+    }
+}
+
+/// An empty sqlite store in memory, for the tests of code that uses the store
+#[cfg(test)]
+pub async fn in_memory() -> Result<Arc<dyn UserStore>> {
+    Ok(Arc::new(sqlite::SqliteUserStore::in_memory().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sqlite store, plus the Postgres one when BRIDGE_TEST_POSTGRES_URL points at a database
+    /// the tests may create schemas in
+    async fn stores() -> Vec<Arc<dyn UserStore>> {
+        let mut stores: Vec<Arc<dyn UserStore>> = vec![Arc::new(
+            sqlite::SqliteUserStore::in_memory().await.unwrap(),
+        )];
+        if let Ok(url) = std::env::var("BRIDGE_TEST_POSTGRES_URL") {
+            stores.push(Arc::new(
+                postgres::PostgresUserStore::test_schema(&url)
+                    .await
+                    .unwrap(),
+            ));
+        }
+        stores
+    }
+
+    #[tokio::test]
+    async fn upsert_link_stores_id_and_names_in_their_columns() {
+        for store in stores().await {
+            assert!(store
+                .upsert_link("alice", 1234, "alice_d", "Alice")
+                .await
+                .unwrap());
+
+            let user = store.find_by_discord_id(1234).await.unwrap().unwrap();
+            assert_eq!(user.irc_nick, "alice");
+            assert_eq!(user.discord_id, Some(1234));
+            assert_eq!(user.discord_nick.as_deref(), Some("alice_d"));
+            assert_eq!(user.discord_name.as_deref(), Some("Alice"));
+            assert!(!user.verified);
+        }
+    }
+
+    #[tokio::test]
+    async fn upsert_link_moves_discord_user_to_new_nick() {
+        for store in stores().await {
+            store.upsert_link("alice", 1234, "a", "A").await.unwrap();
+            store.upsert_link("alice2", 1234, "a", "A").await.unwrap();
+
+            let old = store.find_by_nick("alice").await.unwrap().unwrap();
+            assert_eq!(old.discord_id, None);
+            let user = store.find_by_discord_id(1234).await.unwrap().unwrap();
+            assert_eq!(user.irc_nick, "alice2");
+        }
+    }
+
+    #[tokio::test]
+    async fn upsert_link_does_not_take_over_verified_nick() {
+        for store in stores().await {
+            store.upsert_link("alice", 1234, "a", "A").await.unwrap();
+            store.verify("alice", 1234, "alice").await.unwrap();
+
+            assert!(!store.upsert_link("alice", 5678, "m", "M").await.unwrap());
+            let user = store.find_by_nick("alice").await.unwrap().unwrap();
+            assert_eq!(user.discord_id, Some(1234));
+            assert!(user.verified);
+
+            // Linking again as the same Discord user keeps the verification
+            assert!(store.upsert_link("alice", 1234, "a", "A2").await.unwrap());
+            assert!(store.find_by_nick("alice").await.unwrap().unwrap().verified);
+        }
+    }
+
+    #[tokio::test]
+    async fn verified_users_are_found_by_account() {
+        for store in stores().await {
+            store.upsert_link("alice", 1234, "a", "A").await.unwrap();
+            store.verify("alice", 1234, "alice_account").await.unwrap();
+
+            let user = store
+                .find_irc_user("alice_away", Some("alice_account"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(user.irc_nick, "alice");
+            assert!(user.verified);
+
+            // Someone else on the nick is not treated as verified
+            let impostor = store.find_irc_user("alice", None).await.unwrap().unwrap();
+            assert!(!impostor.verified);
+            let impostor = store
+                .find_irc_user("Alice", Some("mallory"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(!impostor.verified);
+        }
+    }
+
+    #[tokio::test]
+    async fn account_change_revokes_verification() {
+        for store in stores().await {
+            store.upsert_link("alice", 1234, "a", "A").await.unwrap();
+            store.verify("alice", 1234, "alice").await.unwrap();
+
+            assert!(!store
+                .revoke_if_account_changed("alice", "alice")
+                .await
+                .unwrap());
+            assert!(store
+                .revoke_if_account_changed("alice", "mallory")
+                .await
+                .unwrap());
+            assert!(!store.find_by_nick("alice").await.unwrap().unwrap().verified);
+        }
+    }
+
+    #[tokio::test]
+    async fn nicks_that_differ_in_case_are_one_user() {
+        for store in stores().await {
+            store.upsert_link("Alice[m]", 1234, "a", "A").await.unwrap();
+            store.verify("ALICE{M}", 1234, "Alice").await.unwrap();
+            assert!(!store.upsert_link("alice{m}", 5678, "m", "M").await.unwrap());
+            store
+                .set_avatar("aLiCe[M]", Some("https://example.com/a.png"))
+                .await
+                .unwrap();
+
+            let links = store.all_linked().await.unwrap();
+            assert_eq!(links.len(), 1);
+            assert_eq!(links[0].irc_nick, "Alice[m]");
+            assert!(links[0].verified);
+            assert_eq!(
+                links[0].avatar.as_deref(),
+                Some("https://example.com/a.png")
+            );
+
+            // Accounts use the same casemapping
+            let user = store
+                .find_irc_user("alice_away", Some("ALICE"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(user.verified);
+            assert!(
+                store
+                    .find_irc_user("alice{m}", Some("alice"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .verified
+            );
+            assert!(!store
+                .revoke_if_account_changed("alice{m}", "ALICE")
+                .await
+                .unwrap());
+
+            assert!(store
+                .revoke_if_account_changed("Alice{m}", "mallory")
+                .await
+                .unwrap());
+            assert!(store.unlink_irc("ALICE[M]", None).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn set_avatar_creates_and_clears() {
+        for store in stores().await {
+            store
+                .set_avatar("bob", Some("https://example.com/bob.png"))
+                .await
+                .unwrap();
+            let user = store.find_by_nick("bob").await.unwrap().unwrap();
+            assert_eq!(user.avatar.as_deref(), Some("https://example.com/bob.png"));
+            assert_eq!(user.avatar_source(), "custom url");
+
+            store.set_avatar("bob", None).await.unwrap();
+            let user = store.find_by_nick("bob").await.unwrap().unwrap();
+            assert_eq!(user.avatar, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn unlink_keeps_avatar() {
+        for store in stores().await {
+            store.upsert_link("alice", 1234, "a", "A").await.unwrap();
+            store
+                .set_avatar("alice", Some("https://example.com/a.png"))
+                .await
+                .unwrap();
+
+            assert!(store.unlink_discord(1234).await.unwrap());
+            assert!(!store.unlink_discord(1234).await.unwrap());
+            let user = store.find_by_nick("alice").await.unwrap().unwrap();
+            assert_eq!(user.discord_id, None);
+            assert_eq!(user.avatar.as_deref(), Some("https://example.com/a.png"));
+
+            store.upsert_link("alice", 1234, "a", "A").await.unwrap();
+            assert!(store.unlink_irc("alice", None).await.unwrap());
+            assert!(store.all_linked().await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_by_discord_name() {
+        for store in stores().await {
+            store
+                .upsert_link("alice", 1234, "alice_d", "Alice")
+                .await
+                .unwrap();
+
+            assert!(store
+                .find_by_discord_name("ALICE_D")
+                .await
+                .unwrap()
+                .is_some());
+            assert!(store.find_by_discord_name("alice").await.unwrap().is_some());
+            assert!(store.find_by_discord_name("bob").await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn private_routes_follow_the_latest_conversation() {
+        for store in stores().await {
+            let hour = Duration::from_secs(3600);
+            store.open_private_route("Alice", 1234).await.unwrap();
+
+            assert_eq!(
+                store
+                    .find_private_route_by_nick("alice", hour)
+                    .await
+                    .unwrap(),
+                Some(1234)
+            );
+            assert_eq!(
+                store
+                    .find_private_route_by_discord_id(1234, hour)
+                    .await
+                    .unwrap()
+                    .as_deref(),
+                Some("alice")
+            );
+
+            // Someone else messaging the nick takes over its replies
+            store.open_private_route("alice", 5678).await.unwrap();
+            assert_eq!(
+                store
+                    .find_private_route_by_nick("ALICE", hour)
+                    .await
+                    .unwrap(),
+                Some(5678)
+            );
+            assert_eq!(
+                store
+                    .find_private_route_by_discord_id(1234, hour)
+                    .await
+                    .unwrap(),
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_private_routes_expire() {
+        for store in stores().await {
+            store.open_private_route("alice", 1234).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1100)).await;
+
+            assert_eq!(
+                store
+                    .find_private_route_by_nick("alice", Duration::ZERO)
+                    .await
+                    .unwrap(),
+                None
+            );
+            store.expire_private_routes(Duration::ZERO).await.unwrap();
+            assert_eq!(
+                store
+                    .find_private_route_by_nick("alice", Duration::from_secs(3600))
+                    .await
+                    .unwrap(),
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_are_stored_once() {
+        for store in stores().await {
+            let ignore = Ignore::new(IgnoreKind::Hostmask, "*!*@Example.com").unwrap();
+            assert!(store.add_ignore(&ignore).await.unwrap());
+            assert!(!store.add_ignore(&ignore).await.unwrap());
+            assert_eq!(store.all_ignores().await.unwrap(), vec![ignore.clone()]);
+
+            assert!(store.remove_ignore(&ignore).await.unwrap());
+            assert!(!store.remove_ignore(&ignore).await.unwrap());
+            assert!(store.all_ignores().await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn opt_outs_remember_the_notice() {
+        for store in stores().await {
+            let opt_out = Ignore::new(IgnoreKind::Discord, "1234").unwrap();
+            assert!(store.add_opt_out(&opt_out).await.unwrap());
+            assert!(!store.add_opt_out(&opt_out).await.unwrap());
+            assert_eq!(
+                store.all_opt_outs().await.unwrap(),
+                vec![(opt_out.clone(), false)]
+            );
+
+            store.mark_opt_out_notified(&opt_out).await.unwrap();
+            assert_eq!(
+                store.all_opt_outs().await.unwrap(),
+                vec![(opt_out.clone(), true)]
+            );
+
+            // Opting out again after opting in gets a fresh notice
+            assert!(store.remove_opt_out(&opt_out).await.unwrap());
+            assert!(!store.remove_opt_out(&opt_out).await.unwrap());
+            assert!(store.add_opt_out(&opt_out).await.unwrap());
+            assert_eq!(
+                store.all_opt_outs().await.unwrap(),
+                vec![(opt_out.clone(), false)]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn audit_log_is_written() {
+        for store in stores().await {
+            store
+                .record_audit("irc", "alice!a@example.com", "ignore list", true)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn pair_direction_is_stored() {
+        for store in stores().await {
+            assert_eq!(store.pair_direction("#test", 1234).await.unwrap(), None);
+
+            store
+                .set_pair_direction("#test", 1234, Direction::Paused)
+                .await
+                .unwrap();
+            store
+                .set_pair_direction("#test", 1234, Direction::IrcToDiscord)
+                .await
+                .unwrap();
+            assert_eq!(
+                store.pair_direction("#test", 1234).await.unwrap(),
+                Some(Direction::IrcToDiscord)
+            );
+            assert_eq!(store.pair_direction("#test", 5678).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn pair_mentions_are_stored_apart_from_direction() {
+        for store in stores().await {
+            assert!(!store.pair_allows_mentions("#test", 1234).await.unwrap());
+
+            store
+                .set_pair_allows_mentions("#test", 1234, true)
+                .await
+                .unwrap();
+            assert!(store.pair_allows_mentions("#test", 1234).await.unwrap());
+            assert_eq!(
+                store.pair_direction("#test", 1234).await.unwrap(),
+                Some(Direction::Both)
+            );
+
+            store
+                .set_pair_direction("#test", 1234, Direction::Paused)
+                .await
+                .unwrap();
+            assert!(store.pair_allows_mentions("#test", 1234).await.unwrap());
+            assert!(!store.pair_allows_mentions("#test", 5678).await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use sqlx::postgres::{PgConnectOptions, PgPool};
+
+use crate::Result;
+
+#[derive(Clone, Debug)]
+pub struct PostgresUserStore {
+    pub(super) pool: PgPool,
+}
+
+impl PostgresUserStore {
+    pub async fn connect(url: &str, run_migrations: bool) -> Result<Self> {
+        let pool = PgPool::connect_with(url.parse::<PgConnectOptions>()?).await?;
+
+        if run_migrations {
+            println!("LOG: Running postgres migrations");
+            sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+        }
+        Ok(Self { pool })
+    }
+
+    /// A freshly migrated schema of its own, so tests do not see each other's rows
+    #[cfg(test)]
+    pub async fn test_schema(url: &str) -> Result<Self> {
+        let schema = format!("bridge_test_{}", rand::random::<u32>());
+        let pool = PgPool::connect(url).await?;
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+
+        let options = url
+            .parse::<PgConnectOptions>()?
+            .options([("search_path", schema.as_str())]);
+        let pool = PgPool::connect_with(options).await?;
+        sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
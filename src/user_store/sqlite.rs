@@ -0,0 +1,34 @@
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+use crate::Result;
+
+#[derive(Clone, Debug)]
+pub struct SqliteUserStore {
+    pub(super) pool: SqlitePool,
+}
+
+impl SqliteUserStore {
+    pub async fn connect(url: &str, run_migrations: bool) -> Result<Self> {
+        let pool =
+            SqlitePool::connect_with(url.parse::<SqliteConnectOptions>()?.create_if_missing(true))
+                .await?;
+
+        if run_migrations {
+            println!("LOG: Running sqlite migrations");
+            sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+        }
+        Ok(Self { pool })
+    }
+
+    /// A fresh migrated database that only lives as long as the store
+    #[cfg(test)]
+    pub async fn in_memory() -> Result<Self> {
+        // Every connection to sqlite::memory: gets its own database, so only ever open one
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
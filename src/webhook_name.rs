@@ -0,0 +1,126 @@
+//! Turns irc nicks into names Discord accepts for webhook messages. Discord rejects names that
+//! mention Discord or Clyde, contain some characters or are not 1 to 80 characters long, so
+//! instead of losing the message the name is changed just enough to get through.
+
+/// The most characters Discord allows in a webhook username
+const MAX_LENGTH: usize = 80;
+
+/// Words Discord does not allow anywhere in a webhook username, in any case
+const FORBIDDEN_WORDS: [&str; 2] = ["discord", "clyde"];
+
+/// Discord stops seeing a forbidden word once it is split by one of these
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+
+/// Swaps characters Discord does not allow for ones that look like them
+fn look_alike(c: char) -> Option<char> {
+    match c {
+        '@' => Some('＠'),
+        '#' => Some('＃'),
+        ':' => Some('꞉'),
+        '`' => Some('ˋ'),
+        c if c.is_control() => None,
+        c => Some(c),
+    }
+}
+
+/// Splits every forbidden word after its first letter
+fn break_forbidden_words(name: &str) -> String {
+    // Lowercasing ASCII keeps every byte where it was, so matches line up with the original
+    let lower = name.to_ascii_lowercase();
+    let mut splits = FORBIDDEN_WORDS
+        .iter()
+        .flat_map(|word| lower.match_indices(word).map(|(start, _)| start + 1))
+        .collect::<Vec<_>>();
+    splits.sort_unstable();
+
+    let mut broken = String::with_capacity(name.len() + splits.len() * 3);
+    let mut last = 0;
+    for split in splits {
+        broken.push_str(&name[last..split]);
+        broken.push(ZERO_WIDTH_JOINER);
+        last = split;
+    }
+    broken.push_str(&name[last..]);
+    broken
+}
+
+fn replace_characters(text: &str) -> String {
+    text.chars().filter_map(look_alike).collect()
+}
+
+/// Makes a webhook username for `name`, ending in `suffix`, that Discord will accept
+pub fn webhook_username(name: &str, suffix: &str) -> String {
+    let name = replace_characters(name);
+    let mut name = name.trim();
+    if name.is_empty() {
+        name = "_";
+    }
+    let suffix = replace_characters(suffix);
+
+    // Breaking up forbidden words makes the name longer, so cut the nick down until it all fits
+    let mut budget = MAX_LENGTH.saturating_sub(suffix.chars().count()).max(1);
+    loop {
+        let cut = match name.char_indices().nth(budget) {
+            Some((end, _)) => name[..end].trim_end(),
+            None => name,
+        };
+        let username = break_forbidden_words(&format!("{cut}{suffix}"));
+        let length = username.chars().count();
+        if length <= MAX_LENGTH || budget == 1 {
+            return username.chars().take(MAX_LENGTH).collect();
+        }
+        budget = budget.saturating_sub(length - MAX_LENGTH).max(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_nicks_are_kept() {
+        assert_eq!(webhook_username("alice", ""), "alice");
+        assert_eq!(webhook_username("alice", " (IRC)"), "alice (IRC)");
+    }
+
+    #[test]
+    fn forbidden_words_are_broken_up() {
+        assert_eq!(
+            webhook_username("DiscordFan", ""),
+            format!("D{ZERO_WIDTH_JOINER}iscordFan")
+        );
+        assert_eq!(
+            webhook_username("clyde_discord", ""),
+            format!("c{ZERO_WIDTH_JOINER}lyde_d{ZERO_WIDTH_JOINER}iscord")
+        );
+        // A word made where the nick meets the suffix is caught too
+        assert_eq!(
+            webhook_username("disc", "ord"),
+            format!("d{ZERO_WIDTH_JOINER}iscord")
+        );
+    }
+
+    #[test]
+    fn forbidden_characters_are_swapped() {
+        assert_eq!(webhook_username("a@b#c:d```", ""), "a＠b＃c꞉dˋˋˋ");
+        assert_eq!(webhook_username("tab\tbed", ""), "tabbed");
+    }
+
+    #[test]
+    fn empty_names_are_padded() {
+        assert_eq!(webhook_username("", ""), "_");
+        assert_eq!(webhook_username("   ", " (IRC)"), "_ (IRC)");
+    }
+
+    #[test]
+    fn long_names_are_cut_to_fit_the_suffix() {
+        let username = webhook_username(&"a".repeat(100), " (IRC)");
+        assert_eq!(username.chars().count(), MAX_LENGTH);
+        assert!(username.ends_with("a (IRC)"));
+
+        let username = webhook_username(&"discord".repeat(20), " (IRC)");
+        assert!(username.chars().count() <= MAX_LENGTH);
+        assert!(username.ends_with(" (IRC)"));
+        assert!(!username.to_lowercase().contains("discord"));
+    }
+}